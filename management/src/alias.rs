@@ -0,0 +1,165 @@
+use std::{cmp, collections::HashMap};
+
+use crate::crdt::{Entry, LwwMap};
+
+// Alias that a peer claimed for itself.
+//
+// Each peer can only write its own entry in the replicated alias map: the
+// entry keeps the signed PUBLISH_ALIAS or UNPUBLISH_ALIAS message by which the
+// peer claimed or released the alias, so that every peer can verify it no
+// matter who relayed it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AliasClaim {
+    // Empty for a released alias.
+    pub alias: String,
+    // Signed message of the claim.
+    pub envelope: Vec<u8>,
+}
+
+// Aliases that are in use, by alias.
+//
+// Aliases are first come, first served: if several peers claim the same
+// alias, it belongs to the one that claimed it first. Ties are broken by the
+// peer id, so that all peers agree on the owner. Once the owner releases the
+// alias, it passes to the next claim.
+//
+// Peers choose the timestamps of their claims themselves. A claim therefore
+// counts as made no earlier than the membership entry of its peer in
+// `members`, which is signed by an admin. Claims that would take over an
+// alias from its owner are rejected when they arrive, see `takes_over`.
+pub fn owners<M>(aliases: &LwwMap<AliasClaim>, members: &LwwMap<M>) -> HashMap<String, String>
+where
+    M: Clone + Default,
+{
+    let mut owners: HashMap<String, (u64, String)> = HashMap::new();
+    for (peer, entry) in aliases.entries() {
+        if entry.removed {
+            continue;
+        }
+        let claim = rank(members, peer, entry);
+        match owners.get(&entry.value.alias) {
+            Some(owner) if *owner <= claim => {}
+            _ => {
+                owners.insert(entry.value.alias.clone(), claim);
+            }
+        }
+    }
+    owners
+        .into_iter()
+        .map(|(alias, (_, peer))| (alias, peer))
+        .collect()
+}
+
+// Whether a new claim of `peer` would take over an alias that another peer
+// owns, i.e. it was made before the claim of the owner.
+//
+// Without a membership entry, e.g. if the whitelist is empty, nothing bounds
+// the timestamp of a claim. Such claims are rejected, so that an alias is
+// only ever passed on when its owner releases it.
+pub fn takes_over<M>(
+    aliases: &LwwMap<AliasClaim>,
+    members: &LwwMap<M>,
+    peer: &str,
+    entry: &Entry<AliasClaim>,
+) -> bool
+where
+    M: Clone + Default,
+{
+    let owner = match owners(aliases, members).remove(&entry.value.alias) {
+        Some(owner) if owner != peer => owner,
+        _ => return false,
+    };
+    match aliases.get_entry(&owner) {
+        Some(claim) => rank(members, peer, entry) < rank(members, &owner, claim),
+        None => false,
+    }
+}
+
+// Rank of a claim, the lowest owns the alias.
+fn rank<M>(members: &LwwMap<M>, peer: &str, entry: &Entry<AliasClaim>) -> (u64, String)
+where
+    M: Clone + Default,
+{
+    let joined = members.get_entry(peer).map_or(0, |member| member.timestamp);
+    (cmp::max(entry.timestamp, joined), peer.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(aliases: &mut LwwMap<AliasClaim>, peer: &str, alias: &str, timestamp: u64) {
+        let claim = AliasClaim {
+            alias: alias.into(),
+            envelope: Vec::new(),
+        };
+        aliases.insert(peer.into(), claim, timestamp, peer.into());
+    }
+
+    #[test]
+    fn first_claim_owns_the_alias() {
+        let members = LwwMap::<()>::default();
+        let mut aliases = LwwMap::default();
+        claim(&mut aliases, "peer-b", "kitchen", 20);
+        claim(&mut aliases, "peer-a", "kitchen", 10);
+        claim(&mut aliases, "peer-c", "office", 30);
+        let owners = owners(&aliases, &members);
+        assert_eq!(owners["kitchen"], "peer-a");
+        assert_eq!(owners["office"], "peer-c");
+    }
+
+    #[test]
+    fn ties_are_broken_by_the_peer_id() {
+        let members = LwwMap::<()>::default();
+        let mut aliases = LwwMap::default();
+        claim(&mut aliases, "peer-b", "kitchen", 10);
+        claim(&mut aliases, "peer-a", "kitchen", 10);
+        assert_eq!(owners(&aliases, &members)["kitchen"], "peer-a");
+    }
+
+    #[test]
+    fn released_alias_passes_to_the_next_claim() {
+        let members = LwwMap::<()>::default();
+        let mut aliases = LwwMap::default();
+        claim(&mut aliases, "peer-a", "kitchen", 10);
+        claim(&mut aliases, "peer-b", "kitchen", 20);
+        aliases.remove("peer-a".into(), 30, "peer-a".into());
+        assert_eq!(owners(&aliases, &members)["kitchen"], "peer-b");
+    }
+
+    #[test]
+    fn claims_count_no_earlier_than_the_membership() {
+        let mut members = LwwMap::default();
+        members.insert("peer-a".into(), (), 5, "admin".into());
+        members.insert("peer-b".into(), (), 50, "admin".into());
+        let mut aliases = LwwMap::default();
+        claim(&mut aliases, "peer-a", "kitchen", 20);
+        // A backdated claim of a peer that joined later does not win.
+        claim(&mut aliases, "peer-b", "kitchen", 1);
+        assert_eq!(owners(&aliases, &members)["kitchen"], "peer-a");
+    }
+
+    #[test]
+    fn backdated_claims_do_not_take_over() {
+        let members = LwwMap::<()>::default();
+        let mut aliases = LwwMap::default();
+        claim(&mut aliases, "peer-a", "kitchen", 10);
+        let takes = |peer: &str, alias: &str, timestamp| {
+            let entry = Entry {
+                value: AliasClaim {
+                    alias: alias.into(),
+                    envelope: Vec::new(),
+                },
+                timestamp,
+                author: peer.into(),
+                removed: false,
+            };
+            takes_over(&aliases, &members, peer, &entry)
+        };
+        assert!(takes("peer-b", "kitchen", 5));
+        assert!(!takes("peer-b", "kitchen", 20));
+        assert!(!takes("peer-b", "office", 5));
+        // Owners may renew their own claims.
+        assert!(!takes("peer-a", "kitchen", 5));
+    }
+}
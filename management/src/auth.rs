@@ -0,0 +1,168 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+// Milliseconds below the highest seen sequence number within which messages
+// are still accepted, so that messages that arrive out of order are not
+// dropped. Sequence numbers are the times at which messages were sealed, so
+// this bounds how much later a message may arrive than a newer one of the
+// same author.
+const REPLAY_WINDOW: u64 = 60 * 1000;
+
+// Maximum difference in milliseconds between the clocks of two peers.
+// Sequence numbers are based on the time at which a message was sealed, so
+// that messages from authors without a window can be rejected if they were
+// sealed before we started.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60 * 1000;
+
+// Data that is signed by the author of a `SignedMessage`.
+pub fn signed_data(sequence: u64, message: &[u8]) -> Vec<u8> {
+    let mut data = sequence.to_be_bytes().to_vec();
+    data.extend_from_slice(message);
+    data
+}
+
+// Tracks the sequence numbers of the messages that we received from each
+// author, to detect replayed messages.
+pub struct ReplayGuard {
+    windows: HashMap<String, Window>,
+    // Lowest sequence number that is accepted from authors without a window.
+    floor: u64,
+}
+
+struct Window {
+    highest: u64,
+    // Lowest sequence number that is accepted, independent of the window.
+    floor: u64,
+    seen: HashSet<u64>,
+}
+
+impl ReplayGuard {
+    // Create a guard at the given unix time in milliseconds.
+    // Messages that were sealed before that time can only be replays, unless
+    // the clock of their author is behind ours.
+    pub fn new(now: u64) -> Self {
+        ReplayGuard {
+            windows: HashMap::new(),
+            floor: now.saturating_sub(MAX_CLOCK_SKEW),
+        }
+    }
+
+    // Restore the highest sequence numbers that were seen before a restart.
+    // Messages up to these sequence numbers are rejected.
+    pub fn restore(&mut self, highest: &BTreeMap<String, u64>) {
+        for (author, &highest) in highest {
+            let floor = cmp::max(self.floor, highest.saturating_add(1));
+            self.windows.insert(
+                author.clone(),
+                Window {
+                    highest,
+                    floor,
+                    seen: HashSet::new(),
+                },
+            );
+        }
+    }
+
+    // Highest sequence number that was seen from each author.
+    pub fn highest(&self) -> BTreeMap<String, u64> {
+        self.windows
+            .iter()
+            .map(|(author, window)| (author.clone(), window.highest))
+            .collect()
+    }
+
+    // Returns `false` if the message was already seen or is older than the
+    // replay window.
+    pub fn check(&mut self, author: &str, sequence: u64) -> bool {
+        let floor = self.floor;
+        let window = self.windows.entry(author.into()).or_insert_with(|| Window {
+            highest: 0,
+            floor,
+            seen: HashSet::new(),
+        });
+        if sequence < window.floor
+            || sequence.saturating_add(REPLAY_WINDOW) <= window.highest
+            || !window.seen.insert(sequence)
+        {
+            return false;
+        }
+        if sequence > window.highest {
+            window.highest = sequence;
+            window
+                .seen
+                .retain(|s| s.saturating_add(REPLAY_WINDOW) > sequence);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000_000_000;
+
+    #[test]
+    fn rejects_replays() {
+        let mut guard = ReplayGuard::new(NOW);
+        assert!(guard.check("peer", NOW));
+        assert!(!guard.check("peer", NOW));
+        assert!(guard.check("peer", NOW + 1));
+        // Older messages within the window are accepted once.
+        assert!(guard.check("peer", NOW - 1));
+        assert!(!guard.check("peer", NOW - 1));
+        // Authors have separate windows.
+        assert!(guard.check("other", NOW));
+    }
+
+    #[test]
+    fn accepts_messages_that_arrive_late() {
+        let mut guard = ReplayGuard::new(NOW);
+        assert!(guard.check("peer", NOW + 5000));
+        assert!(guard.check("peer", NOW + 1));
+        assert!(guard.check("peer", NOW));
+    }
+
+    #[test]
+    fn rejects_messages_older_than_the_window() {
+        let mut guard = ReplayGuard::new(NOW);
+        assert!(guard.check("peer", NOW + REPLAY_WINDOW));
+        assert!(!guard.check("peer", NOW));
+        assert!(guard.check("peer", NOW + 1));
+    }
+
+    #[test]
+    fn rejects_messages_sealed_before_the_floor() {
+        let mut guard = ReplayGuard::new(NOW);
+        assert!(!guard.check("peer", NOW - MAX_CLOCK_SKEW - 1));
+        assert!(guard.check("peer", NOW - MAX_CLOCK_SKEW));
+    }
+
+    #[test]
+    fn restore_rejects_messages_seen_before_a_restart() {
+        let mut guard = ReplayGuard::new(NOW);
+        assert!(guard.check("peer", NOW + 10));
+
+        let mut restarted = ReplayGuard::new(NOW);
+        restarted.restore(&guard.highest());
+        assert_eq!(restarted.highest(), guard.highest());
+        assert!(!restarted.check("peer", NOW + 10));
+        assert!(!restarted.check("peer", NOW + 5));
+        assert!(restarted.check("peer", NOW + 11));
+        assert!(restarted.check("other", NOW));
+    }
+
+    #[test]
+    fn does_not_overflow_at_the_highest_sequence() {
+        let mut guard = ReplayGuard::new(NOW);
+        assert!(guard.check("peer", u64::MAX));
+        assert!(!guard.check("peer", u64::MAX));
+        assert!(!guard.check("peer", NOW));
+
+        let mut restarted = ReplayGuard::new(NOW);
+        restarted.restore(&guard.highest());
+        assert!(!restarted.check("peer", u64::MAX));
+    }
+}
@@ -1,9 +1,14 @@
 use async_std::io;
 use futures::{
     channel::{mpsc, oneshot},
+    future::BoxFuture,
     prelude::*,
 };
-use management::{Management, UserCommand};
+use management::{Config, Management, ManagementEvent, Role, UserCommand};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Resolves to the result of a command, formatted for the user.
+type ResultFuture = BoxFuture<'static, Result<String, oneshot::Canceled>>;
 
 struct Table {
     peer: Option<String>,
@@ -18,13 +23,9 @@ async fn handle_user_input(
     if let Some(t) = table.as_mut() {
         if msg.is_empty() {
             let table = table.take().unwrap();
-            cmd_tx
-                .send(UserCommand::SendMsg {
-                    peer: table.peer,
-                    message: table.message.into(),
-                })
-                .await
-                .unwrap();
+            let (command, res_fut) = send_msg(table.peer, table.message, None, None);
+            cmd_tx.send(command).await.unwrap();
+            println!("[Management] table: {:?}", res_fut.await.unwrap());
         } else {
             t.message.push_str(&msg);
             t.message.push_str("\n");
@@ -42,31 +43,102 @@ async fn handle_user_input(
     let msg_clone = msg.clone();
     let mut res_fut = None;
     let command = if let Some(msg) = msg.strip_prefix("send ") {
-        UserCommand::SendMsg {
-            peer: None,
-            message: msg.into(),
-        }
+        let (command, rx) = send_msg(None, msg.into(), None, None);
+        let _ = res_fut.insert(rx);
+        command
     } else if let Some(msg) = msg.strip_prefix("sendto ") {
         let parts = msg.split_once(" ").unwrap();
-        UserCommand::SendMsg {
-            peer: Some(parts.0.into()),
-            message: parts.1.into(),
-        }
+        let (command, rx) = send_msg(Some(parts.0.into()), parts.1.into(), None, None);
+        let _ = res_fut.insert(rx);
+        command
+    } else if let Some(msg) = msg.strip_prefix("schedule ") {
+        let parts: Vec<&str> = msg.splitn(4, ' ').collect();
+        let schedule = match parts[..] {
+            [peer, from, until, message] => parse_time(from)
+                .zip(parse_time(until))
+                .map(|(not_before, expires_at)| (peer, not_before, expires_at, message)),
+            _ => None,
+        };
+        let (peer, not_before, expires_at, message) = match schedule {
+            Some(s) => s,
+            None => {
+                println!("[Management] Usage: schedule <peer|*> <from|-> <until|-> <message>");
+                return;
+            }
+        };
+        let peer = (peer != "*").then(|| peer.into());
+        let (command, rx) = send_msg(peer, message.into(), not_before, expires_at);
+        let _ = res_fut.insert(rx);
+        command
     } else if let Some(msg) = msg.strip_prefix("whitelist ") {
-        UserCommand::Whitelist(msg.into())
+        let (tx, rx) = command_result();
+        let _ = res_fut.insert(rx);
+        UserCommand::Whitelist(msg.into(), tx)
+    } else if let Some(msg) = msg.strip_prefix("unwhitelist ") {
+        let (tx, rx) = command_result();
+        let _ = res_fut.insert(rx);
+        UserCommand::Unwhitelist(msg.into(), tx)
     } else if let Some(msg) = msg.strip_prefix("authorize ") {
-        UserCommand::Authorize(msg.into())
+        let (tx, rx) = command_result();
+        let _ = res_fut.insert(rx);
+        UserCommand::AssignRole(msg.into(), Role::Publisher, tx)
+    } else if let Some(msg) = msg.strip_prefix("deauthorize ") {
+        let (tx, rx) = command_result();
+        let _ = res_fut.insert(rx);
+        UserCommand::AssignRole(msg.into(), Role::Viewer, tx)
+    } else if let Some(msg) = msg.strip_prefix("role ") {
+        let (peer, role) = match msg.split_once(' ') {
+            Some(parts) => parts,
+            None => {
+                println!("[Management] Usage: role <peer> <admin|publisher|viewer>");
+                return;
+            }
+        };
+        match parse_role(role) {
+            Some(role) => {
+                let (tx, rx) = command_result();
+                let _ = res_fut.insert(rx);
+                UserCommand::AssignRole(peer.into(), role, tx)
+            }
+            None => {
+                println!("[Management] Unknown role: {}", role);
+                return;
+            }
+        }
     } else if let Some(msg) = msg.strip_prefix("alias ") {
         UserCommand::Alias(msg.into())
+    } else if msg == "unalias" {
+        UserCommand::Unalias
     } else if let Some(msg) = msg.strip_prefix("upgrade self ") {
-        UserCommand::UpgradeSelf(msg.into())
+        let (tx, rx) = command_result();
+        let _ = res_fut.insert(rx);
+        UserCommand::UpgradeSelf(msg.into(), tx)
+    } else if let Some(msg) = msg.strip_prefix("release ") {
+        match msg.split_once(' ') {
+            Some((binary, version)) => UserCommand::SignRelease(binary.into(), version.into()),
+            None => {
+                println!("[Management] Usage: release <binary> <version>");
+                return;
+            }
+        }
     } else if let Some(msg) = msg.strip_prefix("upgrade ") {
-        let parts = msg.split_once(" ").unwrap();
-        UserCommand::Upgrade(parts.0.into(), parts.1.into())
+        // Without a peer the upgrade is offered to all peers.
+        let (tx, rx) = command_result();
+        let _ = res_fut.insert(rx);
+        UserCommand::Upgrade((!msg.is_empty()).then(|| msg.into()), tx)
     } else if let Some(_) = msg.strip_prefix("serve stop") {
         UserCommand::ServeStop
     } else if let Some(msg) = msg.strip_prefix("serve ") {
         UserCommand::Serve(msg.into())
+    } else if let Some(peer) = msg.strip_prefix("show content ") {
+        let (tx, rx) = oneshot::channel();
+        let _ = res_fut.insert(rx.map_ok(|res| format!("{:?}", res)).boxed());
+        UserCommand::GetDisplayContent(peer.into(), tx)
+    } else if msg == "history" || msg.starts_with("history ") {
+        let peer = msg.strip_prefix("history ").map(String::from);
+        let (tx, rx) = oneshot::channel();
+        let _ = res_fut.insert(rx.map_ok(|res| format!("{:?}", res)).boxed());
+        UserCommand::GetHistory(peer, tx)
     } else if let Some(msg) = msg.strip_prefix("show ") {
         match msg {
             "alias" => {
@@ -94,6 +166,11 @@ async fn handle_user_input(
                 let _ = res_fut.insert(rx.map_ok(|res| format!("{:?}", res)).boxed());
                 UserCommand::GetRejected(tx)
             }
+            "displays" => {
+                let (tx, rx) = oneshot::channel();
+                let _ = res_fut.insert(rx.map_ok(|res| format!("{:?}", res)).boxed());
+                UserCommand::GetDisplayStatus(tx)
+            }
             _ => {
                 println!("[Management] Unknown show command: {}", msg);
                 return;
@@ -110,8 +187,109 @@ async fn handle_user_input(
     println!("[Management] {:?}: {:?}", msg_clone, res);
 }
 
+// Create a command for sending a message, and a future for its result.
+fn send_msg(
+    peer: Option<String>,
+    message: String,
+    not_before: Option<u64>,
+    expires_at: Option<u64>,
+) -> (UserCommand, ResultFuture) {
+    let (result_tx, rx) = oneshot::channel();
+    let outcome = if peer.is_some() {
+        "delivered"
+    } else {
+        "published"
+    };
+    let res_fut = rx
+        .map_ok(move |res| match res {
+            Ok(()) => outcome.to_string(),
+            Err(e) => format!("failed: {}", e),
+        })
+        .boxed();
+    let command = UserCommand::SendMsg {
+        peer,
+        message,
+        not_before,
+        expires_at,
+        result_tx,
+    };
+    (command, res_fut)
+}
+
+// Create a channel for the result of a command without output, and a future
+// for the result.
+fn command_result() -> (oneshot::Sender<Result<(), String>>, ResultFuture) {
+    let (tx, rx) = oneshot::channel();
+    let res_fut = rx
+        .map_ok(|res| match res {
+            Ok(()) => "done".to_string(),
+            Err(e) => format!("failed: {}", e),
+        })
+        .boxed();
+    (tx, res_fut)
+}
+
+fn print_event(event: ManagementEvent) {
+    match event {
+        ManagementEvent::InvalidMessage {
+            peer,
+            reason,
+            count,
+        } => println!(
+            "[Management] Warning: invalid message from {} ({}), {} so far",
+            peer, reason, count
+        ),
+        ManagementEvent::PeerBlocked { peer } => {
+            println!("[Management] Warning: blocked {}", peer)
+        }
+    }
+}
+
+// Parse a time for `schedule`: unix time in seconds, `+<seconds>` from now,
+// or `-` for none.
+fn parse_time(time: &str) -> Option<Option<u64>> {
+    if time == "-" {
+        return Some(None);
+    }
+    if let Some(seconds) = time.strip_prefix('+') {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        return seconds
+            .parse::<u64>()
+            .ok()
+            .and_then(|s| now.checked_add(s))
+            .map(Some);
+    }
+    time.parse().ok().map(Some)
+}
+
+fn parse_role(role: &str) -> Option<Role> {
+    match role {
+        "admin" => Some(Role::Admin),
+        "publisher" => Some(Role::Publisher),
+        "viewer" => Some(Role::Viewer),
+        _ => None,
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[Management] Could not load config: {}", e);
+            std::process::exit(1);
+        }
+    };
     let (mut user_input_tx, user_input_rx) = mpsc::channel(0);
+    let (event_tx, mut event_rx) = mpsc::unbounded();
+    user_input_tx
+        .try_send(UserCommand::SubscribeEvents(event_tx))
+        .unwrap();
+    async_std::task::spawn(async move {
+        while let Some(event) = event_rx.next().await {
+            print_event(event);
+        }
+    });
     async_std::task::spawn(async move {
         let mut table = None;
         let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
@@ -120,5 +298,12 @@ fn main() {
             handle_user_input(&mut user_input_tx, &mut table, message).await;
         }
     });
-    async_std::task::block_on(Management::new(user_input_rx).run())
+    let management = match Management::new(config, user_input_rx) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("[Management] Could not start network: {}", e);
+            std::process::exit(1);
+        }
+    };
+    async_std::task::block_on(management.run())
 }
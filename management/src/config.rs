@@ -0,0 +1,242 @@
+use p2p_network::NetworkConfig;
+use serde::Deserialize;
+use std::{fmt, fs, io, path::PathBuf, str::FromStr};
+
+use crate::upgrade;
+use crate::version::UpgradeMode;
+
+// Configuration of the management daemon.
+//
+// It is loaded from the TOML file given with `--config`, and single fields
+// can be overridden on the command line, see `Config::from_args`. Fields
+// that are neither in the file nor on the command line keep their defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub management: ManagementConfig,
+    pub dht: DhtConfig,
+    pub upgrade: UpgradeConfig,
+    pub display: DisplayConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManagementConfig {
+    // Alias that is published when joining the network.
+    pub alias: String,
+    // Buffer size of the channels between the network and management.
+    pub channel_size: usize,
+    // Block peers after this many invalid messages, never if not set.
+    pub block_invalid_after: Option<u32>,
+    // File in which aliases, roles, the content that we stored in the dht and
+    // the records that others stored at us are persisted, so that they
+    // survive a restart.
+    pub state: Option<PathBuf>,
+    // Seconds between two comparisons of the network state with a neighbour.
+    pub sync_interval: u64,
+    // Peers that are admins regardless of the roles assigned in the network.
+    // Roles, whitelist and revocations are only accepted from admins, so the
+    // first admin of a network has to be set here.
+    pub admins: Vec<String>,
+}
+
+impl Default for ManagementConfig {
+    fn default() -> Self {
+        ManagementConfig {
+            alias: String::new(),
+            // it appears there is a deadlock somewhere if the channels have no
+            // buffer... so we need some buffer to clear it.
+            channel_size: 10,
+            block_invalid_after: None,
+            state: None,
+            sync_interval: 60,
+            admins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtConfig {
+    // Whether to request the messages that were stored for us while we were
+    // offline when joining the network.
+    pub request_on_join: bool,
+    // Number of messages that are kept in the history of each display.
+    pub history_depth: usize,
+    // Seconds after which messages are dropped from the history.
+    pub history_retention: u64,
+    // Seconds after which a history lookup is answered with the records that
+    // were fetched so far.
+    pub history_timeout: u64,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            request_on_join: true,
+            history_depth: 10,
+            history_retention: 7 * 24 * 60 * 60,
+            history_timeout: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpgradeConfig {
+    // Peer id of the key with which releases must be signed.
+    pub release_key: Option<String>,
+    pub policy: UpgradeMode,
+    // Peers from which upgrades are accepted. If empty, upgrades from all
+    // peers are accepted.
+    pub sources: Vec<String>,
+    // Seconds within which an upgraded binary has to reach a peer before it
+    // is rolled back. Without any known peers, it only has to keep running.
+    pub health_check_timeout: u64,
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        UpgradeConfig {
+            release_key: None,
+            policy: UpgradeMode::Auto,
+            sources: Vec::new(),
+            health_check_timeout: upgrade::HEALTH_CHECK_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    // Whether received messages are written to the display. Without the
+    // "display" feature they are only printed either way.
+    pub enabled: bool,
+    // Seconds within which a peer has to answer a GET_DISPLAY_CONTENT request.
+    pub content_timeout: u64,
+    // Content that replaces expired content. If not set, the previous content
+    // that is still valid is displayed again.
+    pub default_content: Option<String>,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            enabled: true,
+            content_timeout: 10,
+            default_content: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    // A command line flag is missing its value or the value is invalid.
+    InvalidFlag(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {:?}: {}", path, e),
+            ConfigError::InvalidFlag(flag) => write!(f, "invalid value for {}", flag),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    // Load the config file given with `--config`, or the default config, and
+    // apply the overrides of the other command line flags.
+    pub fn from_args(args: &[String]) -> Result<Self, ConfigError> {
+        let flags = Flags(args);
+        let mut config = match flags.get::<PathBuf>("--config")? {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        let network = &mut config.network;
+        flags.set_some("--private-key", &mut network.private_key)?;
+        flags.set_some("--peer-store", &mut network.peer_store)?;
+        flags.set_list("--listen", &mut network.listen_addrs)?;
+        flags.set("--topic", &mut network.topic)?;
+        flags.set("--max-message-size", &mut network.max_message_size)?;
+        flags.set("--max-file-size", &mut network.max_file_size)?;
+        flags.set("--replication-factor", &mut network.replication_factor)?;
+        flags.set("--record-ttl", &mut network.record_ttl)?;
+
+        let management = &mut config.management;
+        flags.set("--alias", &mut management.alias)?;
+        flags.set("--channel-size", &mut management.channel_size)?;
+        flags.set_some("--block-invalid-after", &mut management.block_invalid_after)?;
+        flags.set_some("--state", &mut management.state)?;
+        flags.set("--sync-interval", &mut management.sync_interval)?;
+        flags.set_list("--admins", &mut management.admins)?;
+
+        let dht = &mut config.dht;
+        flags.set("--request-on-join", &mut dht.request_on_join)?;
+        flags.set("--history-depth", &mut dht.history_depth)?;
+        flags.set("--history-retention", &mut dht.history_retention)?;
+        flags.set("--history-timeout", &mut dht.history_timeout)?;
+
+        let upgrade = &mut config.upgrade;
+        flags.set_some("--release-key", &mut upgrade.release_key)?;
+        flags.set("--upgrade-policy", &mut upgrade.policy)?;
+        flags.set_list("--upgrade-sources", &mut upgrade.sources)?;
+        flags.set("--health-check-timeout", &mut upgrade.health_check_timeout)?;
+
+        let display = &mut config.display;
+        flags.set("--display", &mut display.enabled)?;
+        flags.set("--display-content-timeout", &mut display.content_timeout)?;
+        flags.set_some("--default-content", &mut display.default_content)?;
+
+        Ok(config)
+    }
+}
+
+// Command line flags, each followed by its value.
+struct Flags<'a>(&'a [String]);
+
+impl Flags<'_> {
+    fn get<V: FromStr>(&self, flag: &str) -> Result<Option<V>, ConfigError> {
+        let i = match self.0.iter().position(|arg| arg == flag) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        self.0
+            .get(i + 1)
+            .and_then(|value| value.parse().ok())
+            .map(Some)
+            .ok_or_else(|| ConfigError::InvalidFlag(flag.into()))
+    }
+
+    fn set<V: FromStr>(&self, flag: &str, field: &mut V) -> Result<(), ConfigError> {
+        if let Some(value) = self.get(flag)? {
+            *field = value;
+        }
+        Ok(())
+    }
+
+    fn set_some<V: FromStr>(&self, flag: &str, field: &mut Option<V>) -> Result<(), ConfigError> {
+        if let Some(value) = self.get(flag)? {
+            *field = Some(value);
+        }
+        Ok(())
+    }
+
+    // Set a list from comma separated values.
+    fn set_list(&self, flag: &str, field: &mut Vec<String>) -> Result<(), ConfigError> {
+        if let Some(value) = self.get::<String>(flag)? {
+            *field = value.split(',').map(String::from).collect();
+        }
+        Ok(())
+    }
+}
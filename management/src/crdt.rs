@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+// Version of a value in a `LwwMap`.
+//
+// Of two versions of the same key, the one with the higher timestamp wins.
+// Ties are broken by the author, so that all peers pick the same version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry<V> {
+    pub value: V,
+    // Unix time in milliseconds at which the value was written.
+    pub timestamp: u64,
+    // Peer that wrote the value.
+    pub author: String,
+    // Tombstone of a removed value, kept so that the removal wins over
+    // older versions that are still around in other parts of the network.
+    pub removed: bool,
+}
+
+impl<V> Entry<V> {
+    fn wins_over(&self, other: &Entry<V>) -> bool {
+        (self.timestamp, &self.author) > (other.timestamp, &other.author)
+    }
+}
+
+// Last-writer-wins map with tombstones.
+//
+// Merging the same entries in any order results in the same map, so peers
+// that exchange their entries converge to the same state. Entries are kept in
+// the order of their keys, so that the same map is always serialized the same.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LwwMap<V> {
+    entries: BTreeMap<String, Entry<V>>,
+}
+
+impl<V: Clone + Default> LwwMap<V> {
+    // Current value of a key, `None` if it was never set or is removed.
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries
+            .get(key)
+            .filter(|entry| !entry.removed)
+            .map(|entry| &entry.value)
+    }
+
+    pub fn get_entry(&self, key: &str) -> Option<&Entry<V>> {
+        self.entries.get(key)
+    }
+
+    // Whether the key was removed, as opposed to never being set.
+    pub fn is_removed(&self, key: &str) -> bool {
+        self.entries.get(key).map_or(false, |entry| entry.removed)
+    }
+
+    // All keys that are currently set, with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.removed)
+            .map(|(key, entry)| (key, &entry.value))
+    }
+
+    // All entries including tombstones, as they are exchanged with other peers.
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Entry<V>)> {
+        self.entries.iter()
+    }
+
+    pub fn insert(&mut self, key: String, value: V, timestamp: u64, author: String) -> bool {
+        let entry = Entry {
+            value,
+            timestamp,
+            author,
+            removed: false,
+        };
+        self.merge(key, entry)
+    }
+
+    pub fn remove(&mut self, key: String, timestamp: u64, author: String) -> bool {
+        let entry = Entry {
+            value: V::default(),
+            timestamp,
+            author,
+            removed: true,
+        };
+        self.merge(key, entry)
+    }
+
+    // Merge a remote version of a key.
+    // Returns `true` if it replaced our version.
+    pub fn merge(&mut self, key: String, entry: Entry<V>) -> bool {
+        match self.entries.get(&key) {
+            Some(current) if !entry.wins_over(current) => false,
+            _ => {
+                self.entries.insert(key, entry);
+                true
+            }
+        }
+    }
+
+    // Convert the values, e.g. for persisting them.
+    pub fn map<W, F: Fn(&V) -> W>(&self, f: F) -> LwwMap<W> {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(key, entry)| {
+                let entry = Entry {
+                    value: f(&entry.value),
+                    timestamp: entry.timestamp,
+                    author: entry.author.clone(),
+                    removed: entry.removed,
+                };
+                (key.clone(), entry)
+            })
+            .collect();
+        LwwMap { entries }
+    }
+
+    // Feed the versions of all keys into a digest, to cheaply compare the
+    // maps of two peers. Values are not included, since the same version
+    // always has the same value.
+    pub fn digest(&self, hasher: &mut Sha256) {
+        for (key, entry) in &self.entries {
+            hasher.update(key.as_bytes());
+            hasher.update([0]);
+            hasher.update(entry.timestamp.to_be_bytes());
+            hasher.update(entry.author.as_bytes());
+            hasher.update([0, entry.removed as u8]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: &str, timestamp: u64, author: &str, removed: bool) -> Entry<String> {
+        Entry {
+            value: value.into(),
+            timestamp,
+            author: author.into(),
+            removed,
+        }
+    }
+
+    fn merged(entries: &[(&str, Entry<String>)]) -> LwwMap<String> {
+        let mut map = LwwMap::default();
+        for (key, entry) in entries {
+            map.merge(key.to_string(), entry.clone());
+        }
+        map
+    }
+
+    fn digest(map: &LwwMap<String>) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        map.digest(&mut hasher);
+        hasher.finalize().to_vec()
+    }
+
+    #[test]
+    fn merge_is_independent_of_order() {
+        let entries = [
+            ("a", entry("1", 10, "peer-a", false)),
+            ("a", entry("2", 20, "peer-b", false)),
+            ("a", entry("3", 20, "peer-a", false)),
+            ("b", entry("4", 5, "peer-a", false)),
+            ("b", entry("", 7, "peer-b", true)),
+        ];
+        let forward = merged(&entries);
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        let backward = merged(&reversed);
+
+        assert_eq!(forward.get("a"), Some(&"2".to_string()));
+        assert_eq!(backward.get("a"), Some(&"2".to_string()));
+        assert_eq!(forward.get_entry("b"), backward.get_entry("b"));
+        assert_eq!(digest(&forward), digest(&backward));
+    }
+
+    #[test]
+    fn tombstone_wins_over_older_values() {
+        let mut map = LwwMap::default();
+        assert!(map.insert("a".into(), "1".to_string(), 10, "peer-a".into()));
+        assert!(map.remove("a".into(), 20, "peer-b".into()));
+        assert_eq!(map.get("a"), None);
+        assert!(map.is_removed("a"));
+
+        // A stale version from another peer does not bring the value back.
+        assert!(!map.merge("a".into(), entry("1", 15, "peer-c", false)));
+        assert!(map.is_removed("a"));
+        assert_eq!(map.iter().count(), 0);
+
+        // A newer version does.
+        assert!(map.insert("a".into(), "2".to_string(), 30, "peer-a".into()));
+        assert_eq!(map.get("a"), Some(&"2".to_string()));
+        assert!(!map.is_removed("a"));
+    }
+
+    #[test]
+    fn same_version_is_not_merged_again() {
+        let mut map = LwwMap::default();
+        assert!(map.merge("a".into(), entry("1", 10, "peer-a", false)));
+        assert!(!map.merge("a".into(), entry("1", 10, "peer-a", false)));
+    }
+}
@@ -1,220 +1,247 @@
-use std::collections::HashMap;
+use std::fmt;
 
-pub struct Dht {
-    own_id: String,
-    /// Online whitelisted peers.
-    online_peers: Vec<String>,
+use crate::display;
 
-    /// Peer specific content.
-    peer_content: HashMap<String, String>,
+// Content for offline peers is stored as records in the Kademlia DHT of the
+// network layer. Each author has its own record for each receiver and one for
+// its broadcasts, so that authors never overwrite each other's content, and
+// the receiver picks the newest version among them. A record holds the
+// `History` of the author's content for its receiver, so that a receiver
+// that was offline for a while finds all messages that it missed.
 
-    /// Broadcasted content.
-    broadcast_content: Option<String>,
+// Version of stored content.
+//
+// Of two versions for the same receiver the newer one is kept. Ties are
+// broken by the author and then the digest of the content, so that all
+// peers keep the same version.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    // Sequence number of the original display message, i.e. the unix time in
+    // milliseconds at which it was sent.
+    pub timestamp: u64,
+    pub author: String,
+    pub sha256: Vec<u8>,
 }
 
-impl Dht {
-    pub fn new(own_id: String) -> Self {
-        Dht {
-            own_id: own_id.clone(),
-            online_peers: vec![own_id],
-            peer_content: HashMap::new(),
-            broadcast_content: None,
+impl Version {
+    pub fn new(timestamp: u64, author: String, data: &str) -> Self {
+        Version {
+            timestamp,
+            author,
+            sha256: display::content_digest(data),
         }
     }
+}
 
-    pub fn add_peer(&mut self, peer: String) {
-        if !self.online_peers.contains(&peer) {
-            self.online_peers.push(peer);
-            self.online_peers.sort();
-        }
+#[derive(Clone)]
+pub struct Content {
+    pub data: String,
+    // Signed envelope of the original display message, so that the content
+    // remains attributable to its author.
+    pub envelope: Vec<u8>,
+    pub version: Version,
+    // Schedule of the content, as unix time in seconds, see `ControlMessage`.
+    pub not_before: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
+impl Content {
+    // Whether the content may be displayed at unix time `now` in seconds.
+    pub fn has_started(&self, now: u64) -> bool {
+        self.not_before.map_or(true, |t| t <= now)
     }
 
-    // Add peer to the list of online peers.
-    // Returns list of content to be republished in a StoreMessage request.
-    // Format: (request_target, Vec<(data_owner, data)>)
-    pub fn on_peer_connect(
-        &mut self,
-        peer: String,
-    ) -> Option<(String, Vec<(Option<String>, String)>)> {
-        let peer_index = match self.online_peers.binary_search(&peer) {
-            Ok(_) => return None,
-            Err(index) => index,
-        };
-        self.online_peers.insert(peer_index, peer.clone());
-        let own_index = self
-            .online_peers
-            .binary_search(&self.own_id)
-            .expect("Own Id is always included");
-        let online_count = self.online_peers.len();
-        if online_count <= 2 {
-            let mut content: Vec<_> = self
-                .peer_content
-                .clone()
-                .into_iter()
-                .map(|(r, d)| (Some(r), d))
-                .collect();
-            if let Some(broadcast) = self.broadcast_content.clone() {
-                content.push((None, broadcast));
-            }
-            return Some((peer, content));
-        }
-        let prev_index = (own_index + online_count - 1) % online_count;
-        let prev_prev_index = (own_index + online_count - 2) % online_count;
-        let prev_prev_prev_index = (own_index + online_count - 3) % online_count;
-        let republish = if peer_index == prev_index {
-            // Peer will be inserted into the list directly in front of us.
-            // Part of the data for which we were backup can now be stored at the
-            // new peer.
-            //
-            // E.g. We are 'C' in sorted list A-B-C and 'B2' connects so that the new
-            // order is A-B-B2-C:
-            // Until now we stored the data between A-B and B-C. Now the data between A-B
-            // is stored at B2. We now only need to stored the one between B-B2 and B2-C,
-            // which we already do.
-            let a_id = self.online_peers.get(prev_prev_prev_index).unwrap().clone();
-            let b_id = self.online_peers.get(prev_prev_index).unwrap().clone();
-            let peer_content = self.get_content_between(&a_id, &b_id);
-            for (k, _) in &peer_content {
-                self.peer_content.remove(k);
-            }
-            let mut content: Vec<_> = peer_content
-                .clone()
-                .into_iter()
-                .map(|(r, d)| (Some(r), d))
-                .collect();
-            if let Some(broadcast) = self.broadcast_content.clone() {
-                content.push((None, broadcast));
-            }
-            Some((peer.clone(), content))
-        } else if peer_index == prev_prev_index {
-            // Peer will be inserted into the list two indexes in front of us.
-            // Part of the data for which we were backup can now be stored at the
-            // new peer.
-            //
-            // E.g. We are 'C' in sorted list A-B-C and 'A2' connects so that the new
-            // order is A-A2-B-C:
-            // Until we stored the data between A-B and B-C. Now the data between A-A2
-            // is stored at B2. We now only need to stored the one between A2-B and B-C,
-            // which we already do.
-            let a_id = self.online_peers.get(prev_prev_prev_index).unwrap().clone();
-            let peer_content = self.get_content_between(&a_id, &peer);
-            for (k, _) in &peer_content {
-                self.peer_content.remove(k);
-            }
-            let mut content: Vec<_> = peer_content
-                .clone()
-                .into_iter()
-                .map(|(r, d)| (Some(r), d))
-                .collect();
-            if let Some(broadcast) = self.broadcast_content.clone() {
-                content.push((None, broadcast));
-            }
-            Some((peer.clone(), content))
-        } else {
-            None
-        };
-        republish
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |t| t <= now)
     }
 
-    // Remove peer from the list of online peers.
-    // Returns list of content to be republished in a StoreMessage request.
-    // Format: (request_target, Vec<(data_owner, data)>)
-    pub fn on_peer_disconnect(&mut self, peer: &String) -> Option<(String, Vec<(String, String)>)> {
-        let peer_index = match self.online_peers.binary_search(peer) {
-            Ok(i) => i,
-            Err(_) => return None,
-        };
-        let own_index = self
-            .online_peers
-            .binary_search(&self.own_id)
-            .expect("Own Id is always included");
-        self.online_peers.retain(|p| p != peer);
-        let online_count = self.online_peers.len();
-        let prev_index = (own_index + online_count - 1) % online_count;
-        let next_index = (own_index + online_count + 1) % online_count;
-        if peer_index == prev_index {
-            // Peer preceeding us in the list disconnected.
-            // Republish all data for which the disconnected peer was "first" backup (and thus we "second").
-            //
-            // E.g. We are 'C' in sorted list A-B-C-D-E and 'B' disconnects:
-            // Until now peer D only stores the data for between B-C and C-D. New order is A-C-D-E,
-            // therefore D needs to also store the data that used to be between A and B.
-            let a_id = self.online_peers.get(prev_index).unwrap().clone();
-            let content = self.get_content_between(&a_id, peer);
-            let d_id = self.online_peers.get(next_index).unwrap();
-            Some((d_id.clone(), content))
-        } else if peer_index == next_index {
-            // Peer succeeding us in the list disconnected.
-            // Republish all data for which the disconnected peer was "second" backup (and thus we "first").
-            //
-            // E.g. We are 'C' in sorted list A-B-C-D-E and 'D' disconnects:
-            // Until now peer E only stores the data for between C-D and D-E. New order is A-B-C-E,
-            // therefore E needs to also store the data of peers between B and C.
-            let b_id = self.online_peers.get(prev_index).unwrap().clone();
-            let content = self.get_content_between(&b_id, &self.own_id.clone());
-            let e_id = self.online_peers.get(next_index).unwrap();
-            Some((e_id.clone(), content))
-        } else {
-            None
-        }
+    pub fn is_active(&self, now: u64) -> bool {
+        self.has_started(now) && !self.is_expired(now)
+    }
+}
+
+impl fmt::Debug for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Content")
+            .field("data", &self.data)
+            .field("timestamp", &self.version.timestamp)
+            .field("author", &self.version.author)
+            .field("not_before", &self.not_before)
+            .field("expires_at", &self.expires_at)
+            .finish()
     }
+}
+
+const CONTENT_PREFIX: &str = "/digital-fax/content/";
+const BROADCAST_PREFIX: &str = "/digital-fax/broadcast/";
+
+// Key of the record with the content that `author` stored for `receiver`, or
+// the content it broadcasted if there is no receiver.
+pub fn record_key(receiver: Option<&str>, author: &str) -> String {
+    match receiver {
+        Some(receiver) => format!("{}{}/{}", CONTENT_PREFIX, receiver, author),
+        None => format!("{}{}", BROADCAST_PREFIX, author),
+    }
+}
+
+// Whether the record with `key` holds content for `receiver`, i.e. content
+// that was stored for it or broadcasted.
+pub fn is_record_for(key: &str, receiver: &str) -> bool {
+    match key.strip_prefix(CONTENT_PREFIX) {
+        Some(rest) => rest.split('/').next() == Some(receiver),
+        None => key.starts_with(BROADCAST_PREFIX),
+    }
+}
+
+// Record that another peer stored at us, kept so that it survives a restart
+// of the network layer, whose record store is in memory only.
+#[derive(Debug, Clone)]
+pub struct ReceivedRecord {
+    pub value: Vec<u8>,
+    // Newest version of the content in the record, see `Management::open_record`.
+    pub version: Version,
+    // Unix time in seconds at which the record expires.
+    pub expires: Option<u64>,
+}
+
+// Bounds of a `History`.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryLimits {
+    // Number of entries that are kept.
+    pub depth: usize,
+    // Milliseconds after which entries are dropped.
+    pub retention: u64,
+}
 
-    // Get the content stored for peers whose id is <= start and < end.
-    pub fn get_content_between(&mut self, start: &String, end: &String) -> Vec<(String, String)> {
-        let mut keys = self.peer_content.keys().cloned().collect::<Vec<_>>();
-        if keys.is_empty() {
-            return Vec::new();
+// Content for one receiver, ordered from the oldest to the newest version.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    entries: Vec<Content>,
+}
+
+impl History {
+    // Add content, unless the same version is in the history already.
+    pub fn insert(&mut self, content: Content) {
+        let position = self
+            .entries
+            .binary_search_by(|entry| entry.version.cmp(&content.version));
+        if let Err(index) = position {
+            self.entries.insert(index, content);
         }
-        keys.sort();
-        let start = keys.partition_point(|p| p < start);
-        let end = keys.partition_point(|p| p <= end);
-        keys.rotate_left(start);
-        let between = (end + keys.len() - start) % keys.len();
-        keys.into_iter()
-            .take(between)
-            .map(|k| (k.clone(), self.peer_content.get(&k).unwrap().clone()))
-            .collect()
     }
 
-    pub fn store(&mut self, target: String, data: String) {
-        self.peer_content.insert(target, data);
+    // Drop the entries that expired or are older than the retention at unix
+    // time `now` in milliseconds, and the oldest entries beyond the depth.
+    pub fn prune(&mut self, limits: HistoryLimits, now: u64) {
+        let cutoff = now.saturating_sub(limits.retention);
+        self.entries
+            .retain(|entry| entry.version.timestamp >= cutoff && !entry.is_expired(now / 1000));
+        let excess = self.entries.len().saturating_sub(limits.depth);
+        self.entries.drain(..excess);
+    }
+
+    pub fn remove_oldest(&mut self) -> Option<Content> {
+        (!self.entries.is_empty()).then(|| self.entries.remove(0))
+    }
+
+    pub fn entries(&self) -> &[Content] {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> Vec<Content> {
+        self.entries
     }
 
-    pub fn store_broadcast_content(&mut self, data: String) {
-        for value in self.peer_content.values_mut() {
-            *value = data.clone()
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(timestamp: u64, expires_at: Option<u64>) -> Content {
+        Content {
+            data: format!("content {}", timestamp),
+            envelope: Vec::new(),
+            version: Version::new(timestamp, "peer".into(), "data"),
+            not_before: None,
+            expires_at,
         }
-        self.broadcast_content = Some(data);
     }
 
-    pub fn get_closest_peers(&mut self, target: &String) -> Vec<String> {
-        let index = self
-            .online_peers
+    fn timestamps(history: &History) -> Vec<u64> {
+        history
+            .entries()
             .iter()
-            .clone()
-            .position(|p| p > target)
-            .unwrap_or(0);
-        let mut list = Vec::new();
-        match self.online_peers.get(index) {
-            Some(peer) => list.push(peer.clone()),
-            None => return Vec::new(),
+            .map(|entry| entry.version.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_entries_ordered_and_unique() {
+        let mut history = History::default();
+        history.insert(content(3000, None));
+        history.insert(content(1000, None));
+        history.insert(content(2000, None));
+        history.insert(content(1000, None));
+        assert_eq!(timestamps(&history), [1000, 2000, 3000]);
+        assert_eq!(history.remove_oldest().unwrap().version.timestamp, 1000);
+    }
+
+    #[test]
+    fn prune_drops_the_oldest_entries_beyond_the_depth() {
+        let mut history = History::default();
+        for timestamp in [1000, 2000, 3000] {
+            history.insert(content(timestamp, None));
         }
-        if let Some(next_closest) = self.online_peers.get((index + 1) % self.online_peers.len()) {
-            if next_closest != &list[0] {
-                list.push(next_closest.clone())
-            }
+        let limits = HistoryLimits {
+            depth: 2,
+            retention: u64::MAX,
+        };
+        history.prune(limits, 3000);
+        assert_eq!(timestamps(&history), [2000, 3000]);
+    }
+
+    #[test]
+    fn prune_drops_entries_older_than_the_retention() {
+        let mut history = History::default();
+        for timestamp in [1000, 2000, 3000] {
+            history.insert(content(timestamp, None));
         }
-        list
+        let limits = HistoryLimits {
+            depth: 10,
+            retention: 1000,
+        };
+        history.prune(limits, 3000);
+        assert_eq!(timestamps(&history), [2000, 3000]);
     }
 
-    pub fn get_content(&self, target: &String) -> Option<String> {
-        self.peer_content
-            .get(target)
-            .cloned()
-            .or_else(|| self.broadcast_content.clone())
+    #[test]
+    fn prune_drops_expired_entries() {
+        let mut history = History::default();
+        // Expiry times are in seconds.
+        history.insert(content(1000, Some(2)));
+        history.insert(content(2000, Some(10)));
+        history.insert(content(3000, None));
+        let limits = HistoryLimits {
+            depth: 10,
+            retention: u64::MAX,
+        };
+        history.prune(limits, 5000);
+        assert_eq!(timestamps(&history), [2000, 3000]);
     }
 
-    pub fn get_online_peers(&self) -> &Vec<String> {
-        &self.online_peers
+    #[test]
+    fn selects_records_for_their_receiver() {
+        let key = record_key(Some("peer-a"), "peer-b");
+        assert!(is_record_for(&key, "peer-a"));
+        assert!(!is_record_for(&key, "peer-b"));
+        assert!(!is_record_for(
+            &record_key(Some("peer-ab"), "peer-b"),
+            "peer-a"
+        ));
+        assert!(is_record_for(&record_key(None, "peer-b"), "peer-a"));
     }
 }
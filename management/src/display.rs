@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Number of sent messages whose content is remembered, to resolve the content
+// digests in display results.
+const SENT_CONTENT_CAPACITY: usize = 32;
+
+pub fn content_digest(content: &str) -> Vec<u8> {
+    Sha256::digest(content.as_bytes()).to_vec()
+}
+
+// Return code of a display result for content that was not rendered, since
+// the display is disabled or the binary was built without the "display"
+// feature. Return codes of the display driver are never negative.
+pub const NOT_RENDERED: i32 = -1;
+
+// Current unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Last known state of a display, as reported in its latest display result.
+#[derive(Debug, Clone)]
+pub struct DisplayStatus {
+    // Rendered content, if it was sent by us.
+    pub content: Option<String>,
+    // Hex encoded SHA-256 digest of the rendered content.
+    pub content_sha256: String,
+    // Return code of the display driver, 0 on success, or `NOT_RENDERED`.
+    pub return_code: i32,
+    // Unix time in seconds at which the content was rendered.
+    pub timestamp: u64,
+}
+
+// Message in the history of a display.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub content: String,
+    // Author of the message, by its alias if it has one.
+    pub author: String,
+    // Unix time in seconds at which the message was sent.
+    pub timestamp: u64,
+}
+
+// Content of the messages that we sent recently, by their digest.
+#[derive(Default)]
+pub struct SentContent {
+    order: VecDeque<Vec<u8>>,
+    content: HashMap<Vec<u8>, String>,
+}
+
+impl SentContent {
+    pub fn insert(&mut self, content: String) {
+        let digest = content_digest(&content);
+        if self.content.insert(digest.clone(), content).is_some() {
+            return;
+        }
+        self.order.push_back(digest);
+        if self.order.len() > SENT_CONTENT_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.content.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, digest: &[u8]) -> Option<String> {
+        self.content.get(digest).cloned()
+    }
+}
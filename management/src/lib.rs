@@ -1,7 +1,21 @@
+mod alias;
+mod auth;
+mod config;
+mod crdt;
 mod dht;
+mod display;
 mod management;
+mod permissions;
 mod protocol;
+mod state;
 mod upgrade;
+mod version;
 
+pub use config::{Config, ConfigError};
 pub type Management = management::Management<p2p_network::NetworkComponent>;
+pub use display::DisplayStatus;
+pub use display::HistoryEntry;
+pub use management::ManagementEvent;
 pub use management::UserCommand;
+pub use protocol::DisplayContent;
+pub use protocol::Role;
@@ -1,33 +1,66 @@
-use crate::dht::Dht;
-use crate::protocol::{Alias, ControlMessage, MessageType, NetworkState, StoreMessage};
+use crate::alias::{self, AliasClaim};
+use crate::auth::{self, ReplayGuard};
+use crate::config::{Config, DisplayConfig};
+use crate::crdt::{Entry, LwwMap};
+use crate::dht::{self, Content, History, HistoryLimits, ReceivedRecord, Version};
+use crate::display::{self, DisplayStatus, HistoryEntry, SentContent};
+use crate::permissions::{self, RoleGrant};
+use crate::protocol::{
+    ControlMessage, DisplayContent, DisplayResult, InvalidMessage, MessageType, NetworkState,
+    Release, Role, RoleAssignment, SignedMessage, StateEntry, StoreMessage, StoredHistory,
+};
+use crate::state::{State, StateStore, StoredRecord};
 use crate::upgrade;
+use crate::version::{self, UpgradeDecision, UpgradePolicy};
 use futures::channel::mpsc;
 use futures::channel::oneshot;
+use futures::future::{self, Fuse, FusedFuture};
 use futures::select;
+use futures::FutureExt;
 use futures::StreamExt;
+use futures_timer::Delay;
+use p2p_network::Delivery;
+use p2p_network::NetworkError;
 use p2p_network::NetworkEvent;
 use p2p_network::NetworkLayer;
 use prost::bytes::Bytes;
 use prost::Message;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 use std::thread;
-use std::time;
+use std::time::{self, SystemTime, UNIX_EPOCH};
 use upgrade::UpgradeServer;
 
-pub const CURRENT_VERSION: Option<&str> = option_env!("DF_VERSION");
+// Longest time in seconds that the scheduler sleeps, see `reschedule`.
+const MAX_SCHEDULE_DELAY: u64 = 24 * 60 * 60;
 
 #[derive(Debug)]
 pub enum UserCommand {
     SendMsg {
         peer: Option<String>,
         message: String,
+        // Unix time in seconds before which the message is not displayed.
+        not_before: Option<u64>,
+        // Unix time in seconds at which the message is removed again.
+        expires_at: Option<u64>,
+        // Receives whether the message was delivered, or published if it
+        // has no receiver.
+        result_tx: oneshot::Sender<Result<(), String>>,
     },
-    Whitelist(String),
-    Authorize(String),
+    // Commands that address a peer receive whether they were carried out,
+    // e.g. an error if the peer's name is ambiguous.
+    Whitelist(String, oneshot::Sender<Result<(), String>>),
+    Unwhitelist(String, oneshot::Sender<Result<(), String>>),
+    AssignRole(String, Role, oneshot::Sender<Result<(), String>>),
     Alias(String),
-    UpgradeSelf(String),
-    Upgrade(String, String),
+    // Release our alias, so that other peers can claim it.
+    Unalias,
+    UpgradeSelf(String, oneshot::Sender<Result<(), String>>),
+    // Offer the binary that we serve to a peer, or to all peers if none is given.
+    Upgrade(Option<String>, oneshot::Sender<Result<(), String>>),
+    SignRelease(String, String),
     Serve(String),
     ServeStop,
     GetPeerId(oneshot::Sender<String>),
@@ -36,6 +69,37 @@ pub enum UserCommand {
     GetDiscovered(oneshot::Sender<Vec<String>>),
     GetConnected(oneshot::Sender<Vec<String>>),
     GetRejected(oneshot::Sender<Vec<String>>),
+    GetDisplayStatus(oneshot::Sender<HashMap<String, DisplayStatus>>),
+    // Ask a peer what it currently displays. Resolves to `None` if it displays
+    // nothing yet.
+    GetDisplayContent(
+        String,
+        oneshot::Sender<Result<Option<DisplayContent>, String>>,
+    ),
+    // Message history of a display, of our own if no peer is given. The
+    // history of other displays is looked up in the dht.
+    GetHistory(
+        Option<String>,
+        oneshot::Sender<Result<Vec<HistoryEntry>, String>>,
+    ),
+    // Receive `ManagementEvent`s from now on.
+    SubscribeEvents(mpsc::UnboundedSender<ManagementEvent>),
+}
+
+// Events that are reported to subscribed user interfaces.
+#[derive(Debug, Clone)]
+pub enum ManagementEvent {
+    // A peer sent a message that was dropped. `count` is the number of invalid
+    // messages that were received from the peer so far.
+    InvalidMessage {
+        peer: String,
+        reason: String,
+        count: u32,
+    },
+    // A peer was blocked because it sent too many invalid messages.
+    PeerBlocked {
+        peer: String,
+    },
 }
 
 #[cfg(feature = "display")]
@@ -44,108 +108,348 @@ extern "C" {
     pub fn toDisplay(message: *mut ::std::os::raw::c_char) -> ::std::os::raw::c_int;
 }
 
+// Binary that is being downloaded for an upgrade.
+struct UpgradeDownload {
+    // Release against which the binary is verified.
+    release: Release,
+    // Last reported progress in percent.
+    progress: u64,
+}
+
+// Pending lookup of the history of another display in the dht.
+struct HistoryRequest {
+    // Records that were not fetched yet.
+    keys: HashSet<String>,
+    history: History,
+    tx: oneshot::Sender<Result<Vec<HistoryEntry>, String>>,
+    // Time at which the lookup is answered with the records fetched so far.
+    deadline: time::Instant,
+}
+
 pub struct Management<T> {
     recv_msg_rx: mpsc::Receiver<(String, Vec<u8>, bool)>,
     user_input_rx: mpsc::Receiver<UserCommand>,
     event_rx: mpsc::Receiver<NetworkEvent>,
 
-    authorized_senders: Vec<String>,
-    aliases: HashMap<String, String>,
+    // Replicated network state, see `crdt.rs`.
+    // Whitelisted peers; removed entries are peers that were revoked
+    // network-wide. Values are the signed messages by which admins made the
+    // changes, so that any peer can relay them.
+    members: LwwMap<Vec<u8>>,
+    // Roles of remote peers. Peers without an entry are viewers.
+    roles: LwwMap<RoleGrant>,
+    // Admins from the config, including the peer of the release key.
+    admins: HashSet<String>,
+    // Aliases, by peer, see `alias.rs`.
+    aliases: LwwMap<AliasClaim>,
     alias: String,
 
+    // Anti-entropy: periodically compare the network state with one neighbour
+    // after the other, so that missed changes spread, e.g. after a partition
+    // of the mesh healed.
+    sync_timer: Fuse<Delay>,
+    sync_interval: time::Duration,
+    // Index of the connected peer that we synced with last.
+    sync_index: usize,
+    // Whether we wait for the state of the network that we just joined.
+    joining: bool,
+
     network: T,
     upgrader: UpgradeServer,
 
     discovered_peers: Vec<String>,
     rejected_peers: Vec<String>,
     connected_peers: Vec<String>,
-    listening_addrs: Vec<String>,
 
     upgrade_in_progress: bool,
+    upgrade_download: Option<UpgradeDownload>,
+    // Peer that we asked for its served binary with `upgrade self`.
+    requested_upgrade: Option<String>,
+    // Runs out if an upgraded binary did not confirm its health in time.
+    health_check: Fuse<Delay>,
+    health_check_timeout: time::Duration,
+    // Peer id of the key with which releases must be signed.
+    release_key: Option<String>,
+    upgrade_policy: UpgradePolicy,
 
     local_id: String,
 
-    dht: Dht,
+    // Sequence number of the last message that we sent.
+    sequence: u64,
+    replay_guard: ReplayGuard,
+
+    // Whether to request our stored messages when joining the network.
+    request_on_join: bool,
+    history_limits: HistoryLimits,
+    // Content that we stored in the dht, by receiver, `None` for broadcasts.
+    // New content is added to the history in our record for the receiver.
+    stored_history: BTreeMap<Option<String>, History>,
+    // Records that other peers stored at us, by key.
+    records: BTreeMap<String, ReceivedRecord>,
+    // Records that are larger than this are rejected by the network layer.
+    max_record_size: usize,
+    history_requests: Vec<HistoryRequest>,
+    history_timer: Fuse<Delay>,
+    history_timeout: time::Duration,
+
+    display: DisplayConfig,
+
+    // Last reported state of each display that rendered one of our messages.
+    display_status: HashMap<String, DisplayStatus>,
+    sent_content: SentContent,
+    // Content that we currently display.
+    displayed: Option<DisplayContent>,
+    // Version of the displayed content, so that stored content that we
+    // receive from several replicas only replaces older content.
+    displayed_version: Option<Version>,
+    // Unix time in seconds at which the displayed content expires.
+    displayed_expires_at: Option<u64>,
+    // Content that is displayed once its `not_before` time is reached.
+    scheduled: History,
+    // Runs out when scheduled content is due or the displayed content
+    // expires.
+    schedule_timer: Fuse<Delay>,
+    // Content that was sent to our display. It is not persisted, but fetched
+    // from the dht again when joining the network.
+    history: History,
+    // Pending GET_DISPLAY_CONTENT requests, by the peer they were sent to.
+    display_content_requests: HashMap<String, Vec<oneshot::Sender<Option<DisplayContent>>>>,
+
+    // Number of invalid messages that each peer sent us.
+    invalid_messages: HashMap<String, u32>,
+    // Block peers after this many invalid messages, never if `None`.
+    block_invalid_after: Option<u32>,
+    subscribers: Vec<mpsc::UnboundedSender<ManagementEvent>>,
+
+    // Persists aliases, roles and our stored content, if configured.
+    state_store: Option<StateStore>,
 }
 
 impl<T: NetworkLayer> Management<T> {
-    pub fn new(user_input_rx: mpsc::Receiver<UserCommand>) -> Self {
-        // it appears there is a deadlock in here somewhere... so we need some buffer to clear it.
-        let (recv_msg_tx, recv_msg_rx) = mpsc::channel(10);
-        let (network_event_tx, network_event_rx) = mpsc::channel(10);
+    pub fn new(
+        config: Config,
+        user_input_rx: mpsc::Receiver<UserCommand>,
+    ) -> Result<Self, NetworkError> {
+        let channel_size = config.management.channel_size;
+        let (recv_msg_tx, recv_msg_rx) = mpsc::channel(channel_size);
+        let (network_event_tx, network_event_rx) = mpsc::channel(channel_size);
 
-        let mut private_key: Option<&Path> = None;
-        let mut pk: String;
+        let upgrade_policy = UpgradePolicy {
+            mode: config.upgrade.policy,
+            allowed_sources: config.upgrade.sources,
+        };
 
-        let mut iter = std::env::args().into_iter();
-        loop {
-            let arg = iter.next();
+        let mut admins: HashSet<String> = config.management.admins.into_iter().collect();
+        admins.extend(config.upgrade.release_key.clone());
 
-            match arg {
-                None => {
-                    break;
-                }
-                Some(arg) => {
-                    if arg == "--private-key" {
-                        let n = iter.next();
-                        if n.is_some() {
-                            pk = n.unwrap();
-                            private_key = Some(Path::new(&pk));
-                        }
-                    }
-                }
-            }
-        }
-        let network = T::init(private_key, recv_msg_tx, network_event_tx);
+        let max_record_size = config.network.max_message_size;
+        let network = T::init(config.network, recv_msg_tx, network_event_tx)?;
         let local_id = network.local_peer_id();
 
-        Management {
+        let mut management = Management {
             recv_msg_rx,
             user_input_rx,
             network,
             event_rx: network_event_rx,
-            authorized_senders: Vec::new(),
-            aliases: HashMap::new(),
-            alias: String::new(),
+            members: LwwMap::default(),
+            roles: LwwMap::default(),
+            admins,
+            aliases: LwwMap::default(),
+            alias: config.management.alias,
+            sync_timer: Fuse::terminated(),
+            sync_interval: time::Duration::from_secs(config.management.sync_interval),
+            sync_index: 0,
+            joining: false,
             upgrader: UpgradeServer::new(),
             discovered_peers: Vec::new(),
             rejected_peers: Vec::new(),
             connected_peers: Vec::new(),
-            listening_addrs: Vec::new(),
             upgrade_in_progress: false,
+            upgrade_download: None,
+            requested_upgrade: None,
+            health_check: Fuse::terminated(),
+            health_check_timeout: time::Duration::from_secs(config.upgrade.health_check_timeout),
+            release_key: config.upgrade.release_key,
+            upgrade_policy,
             local_id: local_id.clone(),
-            dht: Dht::new(local_id),
+            sequence: 0,
+            replay_guard: ReplayGuard::new(now_millis()),
+            request_on_join: config.dht.request_on_join,
+            history_limits: HistoryLimits {
+                depth: config.dht.history_depth,
+                retention: config.dht.history_retention * 1000,
+            },
+            stored_history: BTreeMap::new(),
+            records: BTreeMap::new(),
+            max_record_size,
+            history_requests: Vec::new(),
+            history_timer: Fuse::terminated(),
+            history_timeout: time::Duration::from_secs(config.dht.history_timeout),
+            display: config.display,
+            display_status: HashMap::new(),
+            sent_content: SentContent::default(),
+            displayed: None,
+            displayed_version: None,
+            displayed_expires_at: None,
+            scheduled: History::default(),
+            schedule_timer: Fuse::terminated(),
+            history: History::default(),
+            display_content_requests: HashMap::new(),
+            invalid_messages: HashMap::new(),
+            block_invalid_after: config.management.block_invalid_after,
+            subscribers: Vec::new(),
+            state_store: config.management.state.as_deref().map(StateStore::new),
+        };
+        management.restore_state();
+        Ok(management)
+    }
+
+    // Restore the state that was persisted before the last restart.
+    fn restore_state(&mut self) {
+        let state = match self.state_store.as_mut().map(StateStore::load) {
+            Some(Ok(state)) => state,
+            Some(Err(e)) => {
+                println!("[Management] Could not load state: {:?}", e);
+                return;
+            }
+            None => return,
+        };
+        if !state.alias.is_empty() {
+            self.alias = state.alias;
+        }
+        self.members = state
+            .members
+            .map(|envelope| base64::decode(envelope).unwrap_or_default());
+        self.roles = state.roles.map(Into::into);
+        self.aliases = state.aliases.map(Into::into);
+        self.replay_guard.restore(&state.sequences);
+        for value in &state.stored_history {
+            let value = base64::decode(value).unwrap_or_default();
+            for (receiver, content) in self.open_history(&value).unwrap_or_default() {
+                self.stored_history
+                    .entry(receiver)
+                    .or_default()
+                    .insert(content);
+            }
+        }
+        for (key, record) in state.records {
+            let value = base64::decode(&record.value).unwrap_or_default();
+            match self.open_record(&key, &value) {
+                Some(version) => {
+                    let record = ReceivedRecord {
+                        value,
+                        version,
+                        expires: record.expires,
+                    };
+                    self.records.insert(key, record);
+                }
+                None => println!("[Management] Dropping invalid stored record {:?}", key),
+            }
+        }
+    }
+
+    // Persist the state, if it changed.
+    fn save_state(&mut self) {
+        let store = match self.state_store.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        let state = State {
+            alias: self.alias.clone(),
+            members: self.members.map(base64::encode),
+            roles: self.roles.map(Into::into),
+            aliases: self.aliases.map(Into::into),
+            stored_history: self
+                .stored_history
+                .iter()
+                .map(|(receiver, history)| {
+                    base64::encode(stored_history(receiver.clone(), history).encode_to_vec())
+                })
+                .collect(),
+            sequences: self.replay_guard.highest(),
+            records: self
+                .records
+                .iter()
+                .map(|(key, record)| {
+                    let record = StoredRecord {
+                        value: base64::encode(&record.value),
+                        expires: record.expires,
+                    };
+                    (key.clone(), record)
+                })
+                .collect(),
+        };
+        if let Err(e) = store.save(&state) {
+            println!("[Management] Could not save state: {:?}", e);
         }
     }
 
     pub async fn run(mut self) {
-        write_to_display("Initializing".into());
+        self.show("Initializing".into());
+        if UpgradeServer::is_upgrade_pending() {
+            println!("[Management] Running upgraded binary, checking its health");
+            self.health_check = Delay::new(self.health_check_timeout).fuse();
+        }
+        self.track_whitelisted().await;
+        self.restore_records().await;
+        self.sync_timer = Delay::new(self.sync_interval).fuse();
         loop {
             // `Select` is a macro that simultaneously polls items.
             select! {
                 // Poll the swarm for events.
                 // Even if we would not care about the event, we have to poll the
                 // swarm for it to make any progress.
-                (sender, message, broadcasted) = self.recv_msg_rx.select_next_some() => {
-                    self.network_receive(sender, &message, broadcasted).await;
+                (sender, message, _) = self.recv_msg_rx.select_next_some() => {
+                    self.network_receive(sender, &message).await;
                 }
                 // Poll for user input.
                 input = self.user_input_rx.next() => {
                     match input {
                         Some(input) => self.handle_user_command(input).await,
                         None => {
-                            self.shutdown().await;
-                            return
+                            self.save_state();
+                            return;
                         }
                     }
                 }
                 event = self.event_rx.select_next_some() => {
                     self.handle_network_event(event).await;
                 }
+                _ = &mut self.health_check => {
+                    // The binary started and kept running. Reaching a peer
+                    // confirms its health earlier; it is only rolled back if
+                    // there were peers that it could not reach.
+                    if self.has_known_peers() {
+                        println!("[Management] Upgraded binary could not reach any peer");
+                        UpgradeServer::rollback();
+                    }
+                    UpgradeServer::confirm_upgrade();
+                }
+                _ = &mut self.sync_timer => {
+                    self.sync_network_state().await;
+                    self.sync_timer = Delay::new(self.sync_interval).fuse();
+                    let now = display::now();
+                    self.records
+                        .retain(|_, record| record.expires.map_or(true, |t| t > now));
+                }
+                _ = &mut self.history_timer => {
+                    self.expire_history_requests();
+                }
+                _ = &mut self.schedule_timer => {
+                    self.run_schedule().await;
+                }
             }
+            self.save_state();
         }
     }
 
+    // Whether there are other peers that we could connect to, e.g. from the
+    // peer store or discovered in the local network.
+    fn has_known_peers(&self) -> bool {
+        !self.discovered_peers.is_empty()
+            || self.members.iter().any(|(peer, _)| peer != &self.local_id)
+    }
+
     pub async fn handle_network_event(&mut self, event: NetworkEvent) {
         match event {
             NetworkEvent::PeerDiscovered { peer } => {
@@ -154,19 +458,21 @@ impl<T: NetworkLayer> Management<T> {
                 }
             }
             NetworkEvent::ConnectionEstablished { peer } => {
+                if !self.health_check.is_terminated() {
+                    // The network is running and we reached a peer.
+                    UpgradeServer::confirm_upgrade();
+                    self.health_check = Fuse::terminated();
+                }
+
                 // wait a bit for all connections to be established
                 thread::sleep(time::Duration::from_millis(500));
 
-                if self.connected_peers.is_empty()
-                    && self.network.get_whitelisted().await.is_empty()
-                {
+                // The whitelist may have been restored from the peer store, so
+                // we can't rely on it being empty to detect that we just joined.
+                if self.connected_peers.is_empty() {
                     // Connected to first peer in the network.
-                    self.send(
-                        ControlMessage::new(MessageType::PeerConnected, self.local_id.clone()),
-                        None,
-                    )
-                    .await;
                     // Fetch network state
+                    self.joining = true;
                     self.send(
                         ControlMessage::new(MessageType::NetworkSolicitation, ""),
                         Some(peer.clone()),
@@ -175,19 +481,29 @@ impl<T: NetworkLayer> Management<T> {
                 }
 
                 if self.alias != "" {
-                    self.send(
-                        ControlMessage::new(MessageType::PublishAlias, self.alias.clone()),
-                        Some(peer.clone()),
-                    )
-                    .await;
+                    let ctrl = ControlMessage::new(MessageType::PublishAlias, self.alias.clone());
+                    let sealed = self.seal(&ctrl);
+                    let timestamp = self.sequence;
+                    let _ = self
+                        .send_sealed(
+                            MessageType::PublishAlias,
+                            sealed.clone(),
+                            Some(peer.clone()),
+                        )
+                        .await;
+                    // Claims the alias ourselves if it was only configured so
+                    // far, and keeps our existing claim otherwise.
+                    self._handle_message(self.local_id.clone(), ctrl, timestamp, Some(&sealed))
+                        .await;
                 }
 
-                if let Some(current_version) = CURRENT_VERSION {
-                    self.send(
-                        ControlMessage::new(MessageType::NetworkBinaryVersion, current_version),
-                        Some(peer.clone()),
-                    )
-                    .await;
+                if let Some(build) = version::local_build() {
+                    let mut ctrl = ControlMessage::new(
+                        MessageType::NetworkBinaryVersion,
+                        build.version.clone(),
+                    );
+                    ctrl.binary_version = Some(build);
+                    self.send(ctrl, Some(peer.clone())).await;
                 }
 
                 self.rejected_peers.retain(|p| p != &peer);
@@ -196,17 +512,11 @@ impl<T: NetworkLayer> Management<T> {
                 }
             }
             NetworkEvent::ConnectionClosed { peer } => {
-                if self.dht.get_online_peers().contains(&peer) {
-                    let message = ControlMessage::new(MessageType::PeerDisconnected, peer.clone());
-                    self.send(message.clone(), None).await;
-                    self._handle_message(self.local_id.clone(), message, false)
-                        .await;
-                }
+                // Peers that we blocked stay rejected.
                 self.connected_peers.retain(|p| p != &peer);
-                self.rejected_peers.retain(|p| p != &peer);
                 self.discovered_peers.retain(|p| p != &peer);
             }
-            NetworkEvent::ConnectionRejected { peer } => {
+            NetworkEvent::ConnectionRejected { peer, .. } => {
                 if !self.rejected_peers.contains(&peer) {
                     self.rejected_peers.push(peer);
                 }
@@ -216,90 +526,342 @@ impl<T: NetworkLayer> Management<T> {
                 self.rejected_peers.retain(|p| p != &peer);
                 self.discovered_peers.retain(|p| p != &peer);
             }
-            NetworkEvent::NewListenAddress { addr } => {
-                if !self.listening_addrs.contains(&addr) {
-                    self.listening_addrs.push(addr);
+            NetworkEvent::NewListenAddress { .. } => {}
+            NetworkEvent::DeliveryFailed { peer, error } => {
+                println!(
+                    "[Management] Message to {} was not delivered: {}",
+                    peer, error
+                );
+            }
+            NetworkEvent::FileProgress {
+                received, total, ..
+            } => {
+                if let Some(download) = self.upgrade_download.as_mut() {
+                    let progress = received * 100 / cmp::max(total, 1);
+                    if progress >= download.progress + 10 || received == total {
+                        println!("[Management] Downloading upgrade: {}%", progress);
+                        download.progress = progress;
+                    }
+                }
+            }
+            NetworkEvent::FileReceived { peer, .. } => {
+                let download = match self.upgrade_download.take() {
+                    Some(d) => d,
+                    None => return,
+                };
+                println!("[Management] Downloaded upgrade from {}", peer);
+                let release_key = self.release_key.as_deref().unwrap_or_default();
+                if let Err(e) = UpgradeServer::install_update(&download.release, release_key) {
+                    println!("[Management] Upgrade failed: {}", e);
+                    self.upgrade_in_progress = false;
+                }
+            }
+            NetworkEvent::FileTransferFailed { peer, reason, .. } => {
+                if self.upgrade_download.take().is_some() {
+                    println!(
+                        "[Management] Downloading upgrade from {} failed: {}",
+                        peer, reason
+                    );
+                    self.upgrade_in_progress = false;
+                }
+            }
+            NetworkEvent::RecordReceived {
+                key,
+                value,
+                expires,
+            } => {
+                self.accept_record(key, value, expires).await;
+            }
+            NetworkEvent::RecordFetched { key, values } => {
+                let history = self.record_history(&key, values);
+                self.answer_history_requests(&key, &history);
+                if dht::is_record_for(&key, &self.local_id) {
+                    self.show_stored_content(history).await;
+                }
+            }
+        }
+    }
+
+    // What to do about the release `version` that `source` offers.
+    fn decide_upgrade(&self, source: &str, version: &str) -> UpgradeDecision {
+        let announced = match version::parse_version(version) {
+            Some(v) => v,
+            None => return UpgradeDecision::Ignore,
+        };
+        let current = version::CURRENT_VERSION.and_then(version::parse_version);
+        self.upgrade_policy
+            .decide(current.as_ref(), &announced, source)
+    }
+
+    // Download a binary that `peer` provides under `key` and install it once
+    // it is received.
+    async fn download_upgrade(&mut self, peer: String, key: String, release: Release) {
+        if self.upgrade_download.is_some() {
+            println!("[Management] Already downloading an upgrade");
+            return;
+        }
+        println!("[Management] Upgrading from {}...", peer);
+        self.upgrade_in_progress = true;
+        self.upgrade_download = Some(UpgradeDownload {
+            release,
+            progress: 0,
+        });
+        let result = self
+            .network
+            .fetch_file(peer, key, UpgradeServer::get_update_path())
+            .await;
+        if let Err(e) = result {
+            println!("[Management] Could not download upgrade: {}", e);
+            self.upgrade_download = None;
+            self.upgrade_in_progress = false;
+        }
+    }
+
+    // Replace the names in a command by the peer ids that they stand for.
+    // Returns `None` if a name is ambiguous, after returning the error to the
+    // caller of the command.
+    fn resolve_command(&self, command: UserCommand) -> Option<UserCommand> {
+        let command = match command {
+            UserCommand::SendMsg {
+                peer,
+                message,
+                not_before,
+                expires_at,
+                result_tx,
+            } => {
+                let (peer, result_tx) = self.resolve_optional(peer, result_tx)?;
+                UserCommand::SendMsg {
+                    peer,
+                    message,
+                    not_before,
+                    expires_at,
+                    result_tx,
                 }
             }
+            UserCommand::Whitelist(peer, tx) => {
+                let (peer, tx) = self.resolve_for(peer, tx)?;
+                UserCommand::Whitelist(peer, tx)
+            }
+            UserCommand::Unwhitelist(peer, tx) => {
+                let (peer, tx) = self.resolve_for(peer, tx)?;
+                UserCommand::Unwhitelist(peer, tx)
+            }
+            UserCommand::AssignRole(peer, role, tx) => {
+                let (peer, tx) = self.resolve_for(peer, tx)?;
+                UserCommand::AssignRole(peer, role, tx)
+            }
+            UserCommand::UpgradeSelf(peer, tx) => {
+                let (peer, tx) = self.resolve_for(peer, tx)?;
+                UserCommand::UpgradeSelf(peer, tx)
+            }
+            UserCommand::Upgrade(target, tx) => {
+                let (target, tx) = self.resolve_optional(target, tx)?;
+                UserCommand::Upgrade(target, tx)
+            }
+            UserCommand::GetDisplayContent(peer, tx) => {
+                let (peer, tx) = self.resolve_for(peer, tx)?;
+                UserCommand::GetDisplayContent(peer, tx)
+            }
+            UserCommand::GetHistory(peer, tx) => {
+                let (peer, tx) = self.resolve_optional(peer, tx)?;
+                UserCommand::GetHistory(peer, tx)
+            }
+            command => command,
+        };
+        Some(command)
+    }
+
+    // Resolve the name of a peer for a command, or send the error through the
+    // result channel `tx` of the command.
+    fn resolve_for<T>(
+        &self,
+        name: String,
+        tx: oneshot::Sender<Result<T, String>>,
+    ) -> Option<(String, oneshot::Sender<Result<T, String>>)> {
+        match self.resolve_peer(&name) {
+            Ok(peer) => Some((peer, tx)),
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                None
+            }
+        }
+    }
+
+    // Like `resolve_for`, for commands whose peer is optional.
+    fn resolve_optional<T>(
+        &self,
+        name: Option<String>,
+        tx: oneshot::Sender<Result<T, String>>,
+    ) -> Option<(Option<String>, oneshot::Sender<Result<T, String>>)> {
+        match name {
+            Some(name) => self
+                .resolve_for(name, tx)
+                .map(|(peer, tx)| (Some(peer), tx)),
+            None => Some((None, tx)),
         }
     }
 
     pub async fn handle_user_command(&mut self, command: UserCommand) {
+        let command = match self.resolve_command(command) {
+            Some(command) => command,
+            None => return,
+        };
         match command {
-            UserCommand::SendMsg { peer, message } => {
+            UserCommand::SendMsg {
+                peer,
+                message,
+                not_before,
+                expires_at,
+                result_tx,
+            } => {
+                let now = display::now();
+                if expires_at.map_or(false, |t| t <= cmp::max(now, not_before.unwrap_or(now))) {
+                    let _ = result_tx.send(Err("message expires before it is displayed".into()));
+                    return;
+                }
+                let mut ctrl = ControlMessage::new(MessageType::DisplayMessage, message.clone());
+                ctrl.not_before = not_before;
+                ctrl.expires_at = expires_at;
+                let sealed = self.seal(&ctrl);
+                self.sent_content.insert(message.clone());
+                let content = Content {
+                    version: Version::new(self.sequence, self.local_id.clone(), &message),
+                    data: message,
+                    envelope: sealed.clone(),
+                    not_before,
+                    expires_at,
+                };
                 let peer = match peer {
                     Some(peer) => peer,
                     None => {
                         // Publish message to whole network.
-                        self.dht.store_broadcast_content(message.clone());
-                        self.send(
-                            ControlMessage::new(MessageType::DisplayMessage, message),
-                            None,
-                        )
-                        .await;
+                        self.store_content(None, content).await;
+                        let result = self
+                            .send_sealed(MessageType::DisplayMessage, sealed, None)
+                            .await;
+                        let _ = result_tx.send(result.map(|_| ()).map_err(|e| e.to_string()));
                         return;
                     }
                 };
-                self.send(
-                    ControlMessage::new(MessageType::DisplayMessage, message.clone()),
-                    Some(peer.clone()),
-                )
-                .await;
-                for closest in self.dht.get_closest_peers(&peer) {
-                    if closest == self.local_id {
-                        println!("[Management] Storing message for {:?}", peer);
-                        self.dht.store(peer.clone(), message.clone());
-                    } else {
-                        self.send(
-                            ControlMessage {
-                                message_type: MessageType::StoreMessage as i32,
-                                state: None,
-                                message: Some(StoreMessage {
-                                    receiver: Some(peer.clone()),
-                                    data: message.clone(),
-                                }),
-                                payload: String::new(),
-                            },
-                            Some(closest),
-                        )
-                        .await;
+                match self
+                    .send_sealed(MessageType::DisplayMessage, sealed, Some(peer.clone()))
+                    .await
+                {
+                    Ok(delivery) => {
+                        // Don't block on the acknowledgement of the receiver.
+                        async_std::task::spawn(async move {
+                            let result = delivery.await.map_err(|e| e.to_string());
+                            let _ = result_tx.send(result);
+                        });
+                    }
+                    Err(e) => {
+                        let _ = result_tx.send(Err(e.to_string()));
                     }
                 }
+                self.store_content(Some(peer), content).await;
             }
-            UserCommand::Whitelist(new_peer) => {
-                let whitelist = self.network.get_whitelisted().await;
-                if whitelist.contains(&new_peer) {
+            UserCommand::Whitelist(new_peer, tx) => {
+                if let Err(e) = self.check_permitted(MessageType::AddWhitelistPeer) {
+                    let _ = tx.send(Err(e));
                     return;
                 }
-                self.whitelist_peer(new_peer).await;
+                let whitelist = match self.network.get_whitelisted().await {
+                    Ok(w) => w,
+                    Err(e) => {
+                        let _ = tx.send(Err(format!("Could not get whitelist: {}", e)));
+                        return;
+                    }
+                };
+                if !whitelist.contains(&new_peer) {
+                    self.whitelist_peer(new_peer).await;
+                }
+                let _ = tx.send(Ok(()));
             }
-            UserCommand::Authorize(peer) => {
-                let ctrl = ControlMessage::new(MessageType::AddWhitelistSender, peer);
-                self._handle_message(self.local_id.clone(), ctrl.clone(), false)
-                    .await;
-                self.send(ctrl, None).await;
+            UserCommand::Unwhitelist(peer, tx) => {
+                if let Err(e) = self.check_permitted(MessageType::RemoveWhitelistPeer) {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+                let ctrl = ControlMessage::new(MessageType::RemoveWhitelistPeer, peer);
+                self.broadcast_and_apply(ctrl).await;
+                let _ = tx.send(Ok(()));
+            }
+            UserCommand::AssignRole(peer, role, tx) => {
+                if let Err(e) = self.check_permitted(MessageType::AssignRole) {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+                let mut ctrl = ControlMessage::new(MessageType::AssignRole, "");
+                ctrl.role_assignment = Some(RoleAssignment {
+                    peer,
+                    role: role as i32,
+                });
+                self.broadcast_and_apply(ctrl).await;
+                let _ = tx.send(Ok(()));
             }
             UserCommand::Alias(alias) => {
+                match self.alias_owners().get(&alias) {
+                    Some(owner) if owner != &self.local_id => {
+                        println!(
+                            "[Management] Alias {:?} is already taken by {:?}",
+                            alias, owner
+                        );
+                        return;
+                    }
+                    _ => {}
+                }
+                let ctrl = ControlMessage::new(MessageType::PublishAlias, alias.clone());
+                self.broadcast_and_apply(ctrl).await;
+                self.alias = alias;
+            }
+            UserCommand::Unalias => {
+                let ctrl = ControlMessage::new(MessageType::UnpublishAlias, "");
+                self.broadcast_and_apply(ctrl).await;
+                self.alias.clear();
+            }
+            UserCommand::UpgradeSelf(peer, tx) => {
+                // The peer answers with the signed release of the binary that it
+                // serves, which is verified like any other upgrade.
+                self.requested_upgrade = Some(peer.clone());
                 self.send(
-                    ControlMessage::new(MessageType::PublishAlias, alias.clone()),
-                    None,
+                    ControlMessage::new(MessageType::RequestUpgrade, upgrade::SERVED_BINARY_KEY),
+                    Some(peer),
                 )
                 .await;
-                self.alias = alias.into();
+                let _ = tx.send(Ok(()));
             }
-            UserCommand::UpgradeSelf(network_addr) => {
-                let _ = UpgradeServer::upgrade_binary(network_addr.into());
+            UserCommand::Upgrade(target, tx) => {
+                let mut ctrl =
+                    ControlMessage::new(MessageType::Upgrade, upgrade::SERVED_BINARY_KEY);
+                ctrl.release = self.upgrader.release();
+                self.send(ctrl, target).await;
+                let _ = tx.send(Ok(()));
             }
-            UserCommand::Upgrade(a, b) => {
-                let target = (!a.is_empty()).then(|| a);
-                self.send(ControlMessage::new(MessageType::Upgrade, b), target)
-                    .await;
+            UserCommand::SignRelease(file_path, version) => {
+                self.sign_release(Path::new(&file_path), version);
             }
             UserCommand::Serve(file_path) => {
-                self.upgrader.serve(file_path.into()).await;
+                let file_path = Path::new(&file_path);
+                if self.upgrader.serve(file_path) {
+                    let result = self
+                        .network
+                        .provide_file(upgrade::SERVED_BINARY_KEY.into(), file_path.into())
+                        .await;
+                    if let Err(e) = result {
+                        println!("[Management] Could not serve {:?}: {}", file_path, e);
+                        self.upgrader.stop_serving();
+                    }
+                }
             }
             UserCommand::ServeStop => {
-                self.upgrader.stop_serving().await;
+                self.upgrader.stop_serving();
+                let result = self
+                    .network
+                    .stop_providing(upgrade::SERVED_BINARY_KEY.into())
+                    .await;
+                if let Err(e) = result {
+                    println!("[Management] Could not stop serving: {}", e);
+                }
             }
             UserCommand::GetPeerId(tx) => {
                 tx.send(self.network.local_peer_id()).unwrap();
@@ -308,305 +870,1412 @@ impl<T: NetworkLayer> Management<T> {
                 tx.send(self.alias.clone()).unwrap();
             }
             UserCommand::GetAliases(tx) => {
-                tx.send(self.aliases.clone()).unwrap();
+                tx.send(self.alias_owners()).unwrap();
             }
             UserCommand::GetDiscovered(tx) => {
-                tx.send(self.discovered_peers.clone()).unwrap();
+                tx.send(self.names_of(&self.discovered_peers)).unwrap();
             }
             UserCommand::GetConnected(tx) => {
-                tx.send(self.connected_peers.clone()).unwrap();
+                tx.send(self.names_of(&self.connected_peers)).unwrap();
             }
             UserCommand::GetRejected(tx) => {
-                tx.send(self.rejected_peers.clone()).unwrap();
+                tx.send(self.names_of(&self.rejected_peers)).unwrap();
+            }
+            UserCommand::GetDisplayStatus(tx) => {
+                tx.send(self.display_status.clone()).unwrap();
+            }
+            UserCommand::GetHistory(peer, tx) => match peer {
+                Some(peer) if peer != self.local_id => self.request_history(peer, tx).await,
+                _ => {
+                    let _ = tx.send(Ok(self.history_entries(&self.history)));
+                }
+            },
+            UserCommand::SubscribeEvents(tx) => {
+                self.subscribers.push(tx);
+            }
+            UserCommand::GetDisplayContent(peer, tx) => {
+                self.request_display_content(peer, tx).await;
             }
         }
     }
 
-    pub async fn shutdown(mut self) {
-        self.send(
-            ControlMessage::new(MessageType::PeerDisconnected, self.local_id.clone()),
-            None,
-        )
-        .await;
-        thread::sleep(time::Duration::from_millis(500));
+    // Ask `peer` what it currently displays and send the answer through `tx`.
+    async fn request_display_content(
+        &mut self,
+        peer: String,
+        tx: oneshot::Sender<Result<Option<DisplayContent>, String>>,
+    ) {
+        if peer == self.local_id {
+            let _ = tx.send(Ok(self.displayed.clone()));
+            return;
+        }
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let requests = self
+            .display_content_requests
+            .entry(peer.clone())
+            .or_default();
+        requests.retain(|r| !r.is_canceled());
+        requests.push(reply_tx);
+
+        let ctrl = ControlMessage::new(MessageType::GetDisplayContent, "");
+        let sealed = self.seal(&ctrl);
+        let delivery = self
+            .send_sealed(MessageType::GetDisplayContent, sealed, Some(peer))
+            .await;
+        let timeout = time::Duration::from_secs(self.display.content_timeout);
+        // Don't block on the answer of the peer.
+        async_std::task::spawn(async move {
+            let result = match delivery {
+                Ok(delivery) => delivery.await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let result = match result {
+                Ok(()) => async_std::future::timeout(timeout, reply_rx)
+                    .await
+                    .map_err(|_| "timed out".to_string())
+                    .and_then(|reply| reply.map_err(|_| "request canceled".to_string())),
+                Err(e) => Err(e),
+            };
+            let _ = tx.send(result);
+        });
     }
 
     pub async fn whitelist_peer(&mut self, new_peer: String) {
-        self.network.add_whitelisted(self.local_id.clone()).await;
+        if let Err(e) = self.network.add_whitelisted(self.local_id.clone()).await {
+            println!("[Management] Could not whitelist ourselves: {}", e);
+            return;
+        }
 
-        let ctrl = ControlMessage::new(MessageType::AddWhitelistPeer, new_peer.clone());
-        self._handle_message(self.local_id.clone(), ctrl.clone(), false)
-            .await;
+        // Adding the peer to our own whitelist also validates its id, before we
+        // tell the rest of the network.
+        println!("[Management] Whitelisting peer: {:?}", &new_peer);
+        if let Err(e) = self.network.add_whitelisted(new_peer.clone()).await {
+            println!("[Management] Could not whitelist {:?}: {}", new_peer, e);
+            return;
+        }
+
+        let ctrl = ControlMessage::new(MessageType::AddWhitelistPeer, new_peer);
 
         // notify the old peers of the new peer
         thread::sleep(time::Duration::from_millis(200));
-        self.send(ctrl, None).await;
+        self.broadcast_and_apply(ctrl).await;
         thread::sleep(time::Duration::from_millis(200));
     }
 
-    // Receive data from the network.
-    pub async fn network_receive(&mut self, sender: String, data: &[u8], broadcasted: bool) {
-        let bytes = std::boxed::Box::from(data);
-        let decoded = ControlMessage::decode(Bytes::from(bytes)).unwrap();
-        self._handle_message(sender, decoded, broadcasted).await;
+    // Record peers that are whitelisted on the network layer, e.g. restored
+    // from the peer store, but unknown to the replicated network state.
+    // Their entries are older than any change in the network.
+    async fn track_whitelisted(&mut self) {
+        let whitelisted = match self.network.get_whitelisted().await {
+            Ok(w) => w,
+            Err(e) => {
+                println!("[Management] Could not get whitelist: {}", e);
+                return;
+            }
+        };
+        for peer in whitelisted {
+            if self.members.get_entry(&peer).is_none() {
+                self.members
+                    .insert(peer, Vec::new(), 0, self.local_id.clone());
+            }
+        }
     }
 
-    // Send a ControlMessage as a base64 encoded string to the network layer.
-    //
-    // The sender id will automatically be set.
-    pub async fn send(&mut self, msg: ControlMessage, target: Option<String>) {
-        let encoded = msg.encode_to_vec();
-        println!(
-            "[Management] Sending message of type {:?} to {:?}",
-            MessageType::from_i32(msg.message_type).unwrap(),
-            target.clone().unwrap_or("broadcast".into())
-        );
-
-        match target {
-            Some(t) => self.network.send_message(t, encoded.to_vec()).await,
-            None => self.network.publish_message(encoded.to_vec()).await,
+    // Apply a change of the membership of `peer`, if it is newer than the
+    // version that we know.
+    async fn update_member(&mut self, peer: String, entry: Entry<Vec<u8>>) {
+        if is_from_future(entry.timestamp) {
+            println!("[Management] Ignoring whitelist change from the future");
+            return;
+        }
+        let removed = entry.removed;
+        if !self.members.merge(peer.clone(), entry) {
+            return;
+        }
+        if removed {
+            println!("[Management] Revoking peer: {:?}", self.name_of(&peer));
+            self.revoke_peer(peer).await;
+        } else {
+            println!("[Management] Whitelisting peer: {:?}", self.name_of(&peer));
+            if let Err(e) = self.network.add_whitelisted(peer).await {
+                println!("[Management] Could not whitelist peer: {}", e);
+            }
         }
     }
 
-    // Return the alias id resolves to or id itself
-    fn _resolve_alias(&mut self, id: String) -> String {
-        return self.aliases.get(&id).unwrap_or(&id).clone();
+    // Remove a peer from the whitelist. The network layer closes its
+    // connections, which are then reported as closed.
+    pub async fn revoke_peer(&mut self, peer: String) {
+        if peer == self.local_id {
+            println!("[Management] We were revoked from the network");
+            return;
+        }
+        if let Err(e) = self.network.remove_whitelisted(peer.clone()).await {
+            println!(
+                "[Management] Could not remove {:?} from whitelist: {}",
+                peer, e
+            );
+        }
+        self.discovered_peers.retain(|p| p != &peer);
     }
 
-    async fn _handle_message(&mut self, sender: String, msg: ControlMessage, broadcasted: bool) {
-        println!(
-            "[Management] Got message of type {:?} from {:?}",
-            MessageType::from_i32(msg.message_type).unwrap(),
-            &sender,
-        );
+    // Sign a release binary with our identity key and write its release manifest.
+    pub fn sign_release(&self, binary: &Path, version: String) {
+        let sha256 = match upgrade::file_digest(binary) {
+            Ok(d) => d,
+            Err(e) => {
+                println!("[Management] Could not read {:?}: {:?}", binary, e);
+                return;
+            }
+        };
+        let signature = self
+            .network
+            .sign(&upgrade::release_signed_data(&version, &sha256));
+        let release = Release {
+            version,
+            sha256,
+            public_key: self.network.public_key(),
+            signature,
+        };
+        match upgrade::write_manifest(binary, &release) {
+            Ok(()) => println!(
+                "[Management] Signed release {} of {:?}",
+                release.version, binary
+            ),
+            Err(e) => println!("[Management] Could not write release manifest: {:?}", e),
+        }
+    }
 
-        // return if there are authorized senders and the message sender is not one of them
-        if !self.authorized_senders.is_empty()
-            && sender != self.local_id
-            && !self.authorized_senders.contains(&sender)
-        {
-            println!("[Management] Unauthorized sender: {:?}", msg);
+    // Apply a role assignment, if it is newer than the version that we know.
+    fn update_role(&mut self, peer: String, entry: Entry<RoleGrant>) {
+        if is_from_future(entry.timestamp) {
+            println!("[Management] Ignoring role assignment from the future");
             return;
         }
+        let role = entry.value.role;
+        if self.roles.merge(peer.clone(), entry) {
+            println!(
+                "[Management] Assigning role {:?} to {:?}",
+                role,
+                self.name_of(&peer)
+            );
+        }
+    }
 
-        match MessageType::from_i32(msg.message_type) {
-            Some(MessageType::DisplayMessage) => {
-                if broadcasted {
-                    self.dht.store_broadcast_content(msg.payload.clone());
-                }
-                write_to_display(msg.payload);
-            }
-            Some(MessageType::AddWhitelistPeer) => {
-                println!("[Management] Whitelisting peer: {:?}", &msg.payload);
-                self.network.add_whitelisted(msg.payload).await;
-            }
-            Some(MessageType::AddWhitelistSender) => {
-                println!("[Management] Authorizing sender: {:?}", &msg.payload);
-                self.authorized_senders.push(msg.payload);
+    fn role_of(&self, peer: &String) -> Role {
+        if self.admins.contains(peer) {
+            return Role::Admin;
+        }
+        self.roles
+            .get(peer)
+            .map_or(Role::Viewer, |grant| grant.role)
+    }
+
+    fn alias_owners(&self) -> HashMap<String, String> {
+        alias::owners(&self.aliases, &self.members)
+    }
+
+    // Apply a claim or release of the alias of `peer`, given as the signed
+    // message by which the peer made it.
+    fn update_alias(&mut self, peer: String, entry: Entry<AliasClaim>) {
+        if is_from_future(entry.timestamp) {
+            println!("[Management] Ignoring alias claim from the future");
+            return;
+        }
+        if let Some(current) = self.aliases.get_entry(&peer) {
+            // Peers publish their alias again on every new connection, which
+            // must not make their claim younger.
+            if current.removed == entry.removed && current.value.alias == entry.value.alias {
+                return;
             }
-            Some(MessageType::PublishAlias) => {
-                if self.aliases.contains_key(&msg.payload) {
-                    println!(
-                        "[Management] Rejected new alias {:?} for {:?}",
-                        &msg.payload, sender
-                    );
-                    return;
-                }
+        }
+        if !entry.removed && alias::takes_over(&self.aliases, &self.members, &peer, &entry) {
+            println!(
+                "[Management] Ignoring backdated claim of alias {:?} by {:?}",
+                entry.value.alias, peer
+            );
+            return;
+        }
+        let alias = entry.value.alias.clone();
+        let removed = entry.removed;
+        if !self.aliases.merge(peer.clone(), entry) {
+            return;
+        }
+        if removed {
+            println!("[Management] {:?} released its alias", peer);
+            return;
+        }
+        match self.alias_owners().get(&alias) {
+            Some(owner) if owner != &peer => println!(
+                "[Management] Alias {:?} of {:?} is already taken by {:?}",
+                alias, peer, owner
+            ),
+            _ => println!("[Management] Got new alias {:?} for {:?}", alias, peer),
+        }
+    }
 
-                println!(
-                    "[Management] Got new alias {:?} for {:?}",
-                    &msg.payload, sender,
-                );
+    // Unwrap an alias entry of the replicated state. The entry is only
+    // accepted if it carries the signed message of the peer that it is about,
+    // so that peers can not claim or release aliases for others.
+    fn open_alias_entry(&self, entry: StateEntry) -> Option<(String, Entry<AliasClaim>)> {
+        let envelope = entry.envelope?;
+        let (author, sequence, msg) = self.open(&envelope).ok()?;
+        let removed = match MessageType::from_i32(msg.message_type) {
+            Some(MessageType::PublishAlias) => false,
+            Some(MessageType::UnpublishAlias) => true,
+            _ => return None,
+        };
+        let claim = AliasClaim {
+            alias: if removed { String::new() } else { msg.payload },
+            envelope,
+        };
+        let entry = Entry {
+            value: claim,
+            timestamp: sequence,
+            author: author.clone(),
+            removed,
+        };
+        Some((author, entry))
+    }
 
-                // remove previous alias for sender
-                let prev_alias = self._resolve_alias(sender.clone());
-                let _ = self.aliases.remove(&prev_alias);
+    // Unwrap a whitelist entry of the replicated state. The entry is only
+    // accepted if it carries the signed message of an admin.
+    fn open_member_entry(&self, entry: StateEntry) -> Option<(String, Entry<Vec<u8>>)> {
+        let envelope = entry.envelope?;
+        let (author, sequence, msg) = self.open(&envelope).ok()?;
+        let removed = match MessageType::from_i32(msg.message_type) {
+            Some(MessageType::AddWhitelistPeer) => false,
+            Some(MessageType::RemoveWhitelistPeer) => true,
+            _ => return None,
+        };
+        if self.role_of(&author) != Role::Admin {
+            return None;
+        }
+        let entry = Entry {
+            value: envelope,
+            timestamp: sequence,
+            author,
+            removed,
+        };
+        Some((msg.payload, entry))
+    }
 
-                // add new alias for sender
-                self.aliases.insert(msg.payload, sender);
-            }
-            Some(MessageType::NetworkSolicitation) => {
-                let aliases = self
-                    .aliases
-                    .clone()
-                    .into_iter()
-                    .map(|(peer, alias)| Alias { peer, alias })
-                    .collect();
-                let connected = self.dht.get_online_peers().clone();
-                let whitelisted = self.network.get_whitelisted().await;
-                let whitelisted_sender = self.authorized_senders.clone();
-                self.send(
-                    ControlMessage {
-                        message_type: MessageType::State as i32,
-                        state: Some(NetworkState {
-                            whitelisted,
-                            connected,
-                            whitelisted_sender,
-                            aliases,
-                        }),
-                        message: None,
-                        payload: String::new(),
-                    },
-                    Some(sender),
-                )
-                .await;
-            }
-            Some(MessageType::Upgrade) => {
-                println!("[Management] Got upgrade from {}", sender);
-                let _ = UpgradeServer::upgrade_binary(msg.payload);
-            }
-            Some(MessageType::RequestUpgrade) => {
-                println!("[Management] Got upgrade request from {}", sender);
-                if self.upgrade_in_progress {
-                    return;
-                }
+    // Unwrap a role entry of the replicated state. Like whitelist entries, it
+    // is only accepted if it carries the signed message of an admin.
+    fn open_role_entry(&self, entry: StateEntry) -> Option<(String, Entry<RoleGrant>)> {
+        let envelope = entry.envelope?;
+        let (author, sequence, msg) = self.open(&envelope).ok()?;
+        if msg.message_type != MessageType::AssignRole as i32
+            || self.role_of(&author) != Role::Admin
+        {
+            return None;
+        }
+        let assignment = msg.role_assignment?;
+        let grant = RoleGrant {
+            role: Role::from_i32(assignment.role).unwrap_or(Role::Viewer),
+            envelope,
+        };
+        let entry = Entry {
+            value: grant,
+            timestamp: sequence,
+            author,
+            removed: false,
+        };
+        Some((assignment.peer, entry))
+    }
 
-                self.upgrader.serve_binary_once().await;
+    // Digest of the replicated network state, to detect whether two peers
+    // have the same state.
+    fn state_digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        self.members.digest(&mut hasher);
+        self.roles.digest(&mut hasher);
+        self.aliases.digest(&mut hasher);
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
 
-                for addr in self.listening_addrs.clone().iter() {
-                    let mut a = addr.clone();
-                    a.push_str(":");
-                    a.push_str(upgrade::UPGRADE_SERVER_PORT);
+    // Compare our network state with the next connected peer, which sends us
+    // its state if it differs.
+    async fn sync_network_state(&mut self) {
+        if self.connected_peers.is_empty() {
+            return;
+        }
+        self.sync_index = (self.sync_index + 1) % self.connected_peers.len();
+        let peer = self.connected_peers[self.sync_index].clone();
+        let digest = self.state_digest();
+        self.send(
+            ControlMessage::new(MessageType::NetworkSolicitation, digest),
+            Some(peer),
+        )
+        .await;
+    }
 
-                    self.send(
-                        ControlMessage::new(MessageType::Upgrade, a),
-                        Some(sender.clone()),
-                    )
-                    .await;
-                }
-            }
-            Some(MessageType::NetworkBinaryVersion) => {
-                println!("[Management] Got binary version from {}", sender);
-                if CURRENT_VERSION.is_some()
-                    && String::from(CURRENT_VERSION.unwrap()).ge(&msg.payload)
-                {
-                    return;
-                }
-                if self.upgrade_in_progress {
-                    return;
-                }
-                self.upgrade_in_progress = true;
+    fn network_state(&self) -> NetworkState {
+        NetworkState {
+            members: state_entries(&self.members, |entry, envelope| {
+                entry.envelope = Some(envelope.clone());
+            }),
+            roles: state_entries(&self.roles, |entry, grant| {
+                entry.role = grant.role as i32;
+                entry.envelope = Some(grant.envelope.clone());
+            }),
+            aliases: state_entries(&self.aliases, |entry, claim| {
+                entry.alias = claim.alias.clone();
+                entry.envelope = Some(claim.envelope.clone());
+            }),
+        }
+    }
 
-                self.send(
-                    ControlMessage::new(MessageType::RequestUpgrade, ""),
-                    Some(sender),
-                )
-                .await;
-            }
-            Some(MessageType::PeerConnected) => {
-                if let Some((target, republish)) = self.dht.on_peer_connect(msg.payload) {
-                    println!(
-                        "[Management] Republishing data to {:?}: {:?}",
-                        target, republish
-                    );
-                    for (peer, data) in republish {
-                        self.send(
-                            ControlMessage {
-                                message_type: MessageType::StoreMessage as i32,
-                                state: None,
-                                message: Some(StoreMessage {
-                                    receiver: peer,
-                                    data,
-                                }),
-                                payload: String::new(),
-                            },
-                            Some(target.clone()),
-                        )
-                        .await;
-                    }
+    // Whether `sender` is permitted to send us `msg`.
+    fn is_permitted(&self, sender: &String, msg: &ControlMessage) -> bool {
+        let message_type = match MessageType::from_i32(msg.message_type) {
+            Some(t) => t,
+            None => return false,
+        };
+        permissions::is_permitted(self.role_of(sender), message_type)
+    }
+
+    // Error if our own role does not permit messages of the given type, which
+    // all other peers would reject.
+    fn check_permitted(&self, message_type: MessageType) -> Result<(), String> {
+        let role = self.role_of(&self.local_id);
+        if !permissions::is_permitted(role, message_type) {
+            return Err(format!(
+                "Our role {:?} does not permit {:?} messages",
+                role, message_type
+            ));
+        }
+        Ok(())
+    }
+
+    // Write to the display, unless it is disabled in the config.
+    fn show(&self, data: String) -> i32 {
+        if !self.display.enabled {
+            println!("[DISPLAY] Display disabled, not showing: {:?}", data);
+            return display::NOT_RENDERED;
+        }
+        write_to_display(data)
+    }
+
+    // Report an event to all subscribers, and forget subscribers that are gone.
+    fn emit(&mut self, event: ManagementEvent) {
+        self.subscribers
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    // Count an invalid message of `peer`, and block the peer if it sent too
+    // many of them.
+    async fn drop_invalid(&mut self, peer: String, reason: InvalidMessage) {
+        println!(
+            "[Management] Dropping invalid message from {:?}: {}",
+            peer, reason
+        );
+        let count = self.invalid_messages.entry(peer.clone()).or_insert(0);
+        *count += 1;
+        let count = *count;
+        self.emit(ManagementEvent::InvalidMessage {
+            peer: peer.clone(),
+            reason: reason.to_string(),
+            count,
+        });
+        if self.block_invalid_after != Some(count) {
+            return;
+        }
+        println!(
+            "[Management] Blocking {:?} after {} invalid messages",
+            peer, count
+        );
+        if let Err(e) = self.network.block_peer(peer.clone()).await {
+            println!("[Management] Could not block peer: {}", e);
+            return;
+        }
+        if !self.rejected_peers.contains(&peer) {
+            self.rejected_peers.push(peer.clone());
+        }
+        self.emit(ManagementEvent::PeerBlocked { peer });
+    }
+
+    // Store content in the dht, so that its receiver finds it when it comes
+    // back online. Broadcast content is found by all peers that were offline.
+    //
+    // The record holds the history of the content that we stored for the
+    // receiver. The oldest content is dropped if the record gets too large.
+    async fn store_content(&mut self, receiver: Option<String>, content: Content) {
+        let history = self.stored_history.entry(receiver.clone()).or_default();
+        history.insert(content);
+        history.prune(self.history_limits, display::now() * 1000);
+        self.publish_history(receiver).await;
+    }
+
+    // Put our record with the history of content for `receiver` into the dht.
+    async fn publish_history(&mut self, receiver: Option<String>) {
+        let key = dht::record_key(receiver.as_deref(), &self.local_id);
+        let history = self.stored_history.entry(receiver.clone()).or_default();
+        let value = loop {
+            let value = stored_history(receiver.clone(), history).encode_to_vec();
+            if value.len() <= self.max_record_size || history.len() <= 1 {
+                break value;
+            }
+            history.remove_oldest();
+        };
+        // The record is dropped once all of its content expired.
+        let expires = history
+            .entries()
+            .iter()
+            .try_fold(0, |last, content| {
+                content.expires_at.map(|t| cmp::max(last, t))
+            })
+            .map(|t| UNIX_EPOCH + time::Duration::from_secs(t));
+        if let Err(e) = self.network.put_record(key, value, expires).await {
+            println!("[Management] Could not store content: {}", e);
+        }
+    }
+
+    // The record store of the network layer is not persisted. After a
+    // restart, the records that other peers stored at us are stored again,
+    // and our own records are published again.
+    async fn restore_records(&mut self) {
+        let now = display::now();
+        self.records
+            .retain(|_, record| record.expires.map_or(true, |t| t > now));
+        for (key, record) in &self.records {
+            let expires = record
+                .expires
+                .map(|t| UNIX_EPOCH + time::Duration::from_secs(t));
+            let result = self
+                .network
+                .store_record(key.clone(), record.value.clone(), expires)
+                .await;
+            if let Err(e) = result {
+                println!("[Management] Could not restore record {:?}: {}", key, e);
+            }
+        }
+        for history in self.stored_history.values_mut() {
+            history.prune(self.history_limits, now * 1000);
+        }
+        self.stored_history
+            .retain(|_, history| !history.entries().is_empty());
+        let receivers: Vec<_> = self.stored_history.keys().cloned().collect();
+        for receiver in receivers {
+            self.publish_history(receiver).await;
+        }
+    }
+
+    // Store a record that another peer put into the dht, unless it is invalid
+    // or older than the version that we store already. This keeps peers from
+    // overwriting the records of others.
+    async fn accept_record(&mut self, key: String, value: Vec<u8>, expires: Option<SystemTime>) {
+        let version = match self.open_record(&key, &value) {
+            Some(version) => version,
+            None => {
+                println!("[Management] Rejecting invalid record {:?}", key);
+                return;
+            }
+        };
+        if let Some(current) = self.records.get(&key) {
+            if current.version > version {
+                println!("[Management] Rejecting outdated record {:?}", key);
+                return;
+            }
+        }
+        let result = self
+            .network
+            .store_record(key.clone(), value.clone(), expires)
+            .await;
+        if let Err(e) = result {
+            println!("[Management] Could not store record {:?}: {}", key, e);
+            return;
+        }
+        let record = ReceivedRecord {
+            value,
+            version,
+            expires: expires
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        };
+        self.records.insert(key, record);
+    }
+
+    // Verify a record of the dht. It must only hold valid content of the
+    // author and receiver of its key.
+    // Returns the newest version of its content.
+    fn open_record(&self, key: &str, value: &[u8]) -> Option<Version> {
+        let history = StoredHistory::decode(Bytes::copy_from_slice(value)).ok()?;
+        let mut newest = None;
+        for message in history.messages {
+            let receiver = message.receiver.clone();
+            let content = self.stored_content(message)?;
+            if dht::record_key(receiver.as_deref(), &content.version.author) != key {
+                return None;
+            }
+            newest = cmp::max(newest, Some(content.version));
+        }
+        newest
+    }
+
+    fn publishers(&self) -> Vec<String> {
+        let mut publishers: Vec<String> = self.admins.iter().cloned().collect();
+        for (peer, grant) in self.roles.iter() {
+            if matches!(grant.role, Role::Publisher | Role::Admin) && !self.admins.contains(peer) {
+                publishers.push(peer.clone());
+            }
+        }
+        publishers
+    }
+
+    // Look up the content that publishers stored for us while we were offline.
+    async fn request_stored_content(&mut self) {
+        for publisher in self.publishers() {
+            for receiver in [Some(self.local_id.as_str()), None] {
+                let key = dht::record_key(receiver, &publisher);
+                if let Err(e) = self.network.get_record(key).await {
+                    println!("[Management] Could not request stored content: {}", e);
+                    return;
                 }
             }
-            Some(MessageType::PeerDisconnected) => {
-                if msg.payload == self.local_id {
-                    // The network wrongly assumes us to be offline, most likely
-                    // because a connection timed out.
-                    // Inform the network that we are still online.
-                    self.send(
-                        ControlMessage::new(MessageType::PeerConnected, self.local_id.clone()),
-                        None,
-                    )
-                    .await;
+        }
+    }
+
+    // Look up the history of the display of `peer` in the dht, and send it
+    // through `tx` once all records are fetched.
+    async fn request_history(
+        &mut self,
+        peer: String,
+        tx: oneshot::Sender<Result<Vec<HistoryEntry>, String>>,
+    ) {
+        let mut keys = HashSet::new();
+        for publisher in self.publishers() {
+            keys.insert(dht::record_key(Some(&peer), &publisher));
+            keys.insert(dht::record_key(None, &publisher));
+        }
+        if keys.is_empty() {
+            let _ = tx.send(Ok(Vec::new()));
+            return;
+        }
+        for key in &keys {
+            if let Err(e) = self.network.get_record(key.clone()).await {
+                let _ = tx.send(Err(e.to_string()));
+                return;
+            }
+        }
+        self.history_requests.push(HistoryRequest {
+            keys,
+            history: History::default(),
+            tx,
+            deadline: time::Instant::now() + self.history_timeout,
+        });
+        if self.history_timer.is_terminated() {
+            self.history_timer = Delay::new(self.history_timeout).fuse();
+        }
+    }
+
+    // Add the content of a fetched record to the history lookups that wait
+    // for it, and answer the lookups that are complete.
+    fn answer_history_requests(&mut self, key: &str, record: &History) {
+        for request in self.history_requests.iter_mut() {
+            if request.keys.remove(key) {
+                for content in record.entries() {
+                    request.history.insert(content.clone());
+                }
+            }
+        }
+        let (complete, pending) = std::mem::take(&mut self.history_requests)
+            .into_iter()
+            .partition(|request| request.keys.is_empty());
+        self.history_requests = pending;
+        for request in complete {
+            self.answer_history_request(request);
+        }
+    }
+
+    // Answer the history lookups whose records were not all fetched in time
+    // with the records fetched so far.
+    fn expire_history_requests(&mut self) {
+        let now = time::Instant::now();
+        let (expired, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.history_requests)
+            .into_iter()
+            .partition(|request| request.deadline <= now);
+        self.history_requests = pending;
+        for request in expired {
+            println!(
+                "[Management] History lookup timed out, {} records missing",
+                request.keys.len()
+            );
+            self.answer_history_request(request);
+        }
+        self.history_timer = match self.history_requests.iter().map(|r| r.deadline).min() {
+            Some(deadline) => Delay::new(deadline.saturating_duration_since(now)).fuse(),
+            None => Fuse::terminated(),
+        };
+    }
+
+    fn answer_history_request(&self, mut request: HistoryRequest) {
+        request
+            .history
+            .prune(self.history_limits, display::now() * 1000);
+        let _ = request.tx.send(Ok(self.history_entries(&request.history)));
+    }
+
+    fn history_entries(&self, history: &History) -> Vec<HistoryEntry> {
+        history
+            .entries()
+            .iter()
+            .map(|content| HistoryEntry {
+                content: content.data.clone(),
+                author: self.name_of(&content.version.author),
+                timestamp: content.version.timestamp / 1000,
+            })
+            .collect()
+    }
+
+    // Open the value of a record that was fetched from the dht.
+    fn record_history(&self, key: &str, values: Vec<Vec<u8>>) -> History {
+        let mut history = History::default();
+        for value in values {
+            let entries = match self.open_history(&value) {
+                Some(entries) => entries,
+                None => {
+                    println!("[Management] Ignoring undecodable record {:?}", key);
+                    continue;
+                }
+            };
+            for (receiver, content) in entries {
+                // Records can only be stored under the key of their own author
+                // and receiver, so that nobody can pass off content as someone
+                // else's.
+                if dht::record_key(receiver.as_deref(), &content.version.author) != key {
+                    println!(
+                        "[Management] Ignoring content stored under a wrong key {:?}",
+                        key
+                    );
+                    continue;
+                }
+                history.insert(content);
+            }
+        }
+        history.prune(self.history_limits, display::now() * 1000);
+        history
+    }
+
+    // Decode a `StoredHistory`, skipping content with an invalid version.
+    // Returns the receiver and the content of each entry.
+    fn open_history(&self, value: &[u8]) -> Option<Vec<(Option<String>, Content)>> {
+        let history = StoredHistory::decode(Bytes::copy_from_slice(value)).ok()?;
+        let mut entries = Vec::new();
+        for message in history.messages {
+            let receiver = message.receiver.clone();
+            match self.stored_content(message) {
+                Some(content) => entries.push((receiver, content)),
+                None => println!("[Management] Ignoring stored content with an invalid version"),
+            }
+        }
+        Some(entries)
+    }
+
+    // Display content that was stored for us in the dht. All of it is added
+    // to our history, and content that is not due yet to the schedule, but
+    // only the newest active content is displayed, unless we display newer
+    // content already.
+    async fn show_stored_content(&mut self, history: History) {
+        // A record only holds content of its own author.
+        let author = match history.entries().first() {
+            Some(content) => content.version.author.clone(),
+            None => return,
+        };
+        if !permissions::is_permitted(self.role_of(&author), MessageType::DisplayMessage) {
+            println!(
+                "[Management] Ignoring stored content of unauthorized {:?}",
+                self.name_of(&author)
+            );
+            return;
+        }
+        let now = display::now();
+        let mut latest = None;
+        for content in history.into_entries() {
+            self.history.insert(content.clone());
+            if !content.has_started(now) {
+                self.schedule(content);
+            } else if !content.is_expired(now) {
+                latest = Some(content);
+            }
+        }
+        self.history.prune(self.history_limits, now * 1000);
+        self.reschedule();
+        let latest = match latest {
+            Some(c) => c,
+            None => return,
+        };
+        let timestamp = latest.version.timestamp;
+        let envelope = latest.envelope.clone();
+        let mut ctrl = ControlMessage::new(MessageType::DisplayMessage, latest.data.clone());
+        ctrl.not_before = latest.not_before;
+        ctrl.expires_at = latest.expires_at;
+        ctrl.message = Some(store_message(None, latest));
+        self._handle_message(author, ctrl, timestamp, Some(&envelope))
+            .await;
+    }
+
+    // Write content to our display, and report to its author that it is on
+    // our display.
+    async fn render(&mut self, content: Content) {
+        let author = content.version.author.clone();
+        self.displayed = Some(DisplayContent {
+            payload: content.data.clone(),
+            author: author.clone(),
+            timestamp: display::now(),
+        });
+        self.displayed_version = Some(content.version);
+        self.displayed_expires_at = content.expires_at;
+        self.reschedule();
+        let result = DisplayResult {
+            content_sha256: display::content_digest(&content.data),
+            return_code: self.show(content.data),
+            timestamp: display::now(),
+        };
+        if author != self.local_id {
+            // Report to the author that its content is on our display.
+            let mut ctrl = ControlMessage::new(MessageType::DisplayResult, "");
+            ctrl.display_result = Some(result);
+            self.send(ctrl, Some(author)).await;
+        }
+    }
+
+    // Display scheduled content that is due, and replace the displayed
+    // content if it expired.
+    async fn run_schedule(&mut self) {
+        let now = display::now();
+        let (due, pending): (Vec<Content>, Vec<Content>) = std::mem::take(&mut self.scheduled)
+            .into_entries()
+            .into_iter()
+            .partition(|content| content.has_started(now));
+        for content in pending {
+            self.scheduled.insert(content);
+        }
+        // Of the due content, only the newest is displayed.
+        let newest = due.into_iter().filter(|c| !c.is_expired(now)).last();
+        if let Some(content) = newest {
+            if self.displayed_version.as_ref() < Some(&content.version) {
+                self.render(content).await;
+            }
+        }
+        if self.displayed_expires_at.map_or(false, |t| t <= now) {
+            self.revert_display().await;
+        }
+        self.reschedule();
+    }
+
+    // Replace expired content on our display by the default content of the
+    // config, or else by the newest content in our history that is still
+    // active. The display is cleared if there is none.
+    async fn revert_display(&mut self) {
+        self.displayed_expires_at = None;
+        if let Some(default) = self.display.default_content.clone() {
+            println!("[Management] Displayed content expired, showing the default content");
+            self.displayed = Some(DisplayContent {
+                payload: default.clone(),
+                author: self.local_id.clone(),
+                timestamp: display::now(),
+            });
+            self.show(default);
+            return;
+        }
+        let now = display::now();
+        let previous = self
+            .history
+            .entries()
+            .iter()
+            .rev()
+            .find(|c| c.is_active(now) && Some(&c.version) != self.displayed_version.as_ref())
+            .cloned();
+        match previous {
+            Some(content) => {
+                println!("[Management] Displayed content expired, showing the previous content");
+                self.render(content).await;
+            }
+            None => {
+                println!("[Management] Displayed content expired, clearing the display");
+                self.displayed = None;
+                self.show(String::new());
+            }
+        }
+    }
+
+    // Add content that is not due yet to the schedule, which is bounded like
+    // the history.
+    fn schedule(&mut self, content: Content) {
+        self.scheduled.insert(content);
+        self.scheduled
+            .prune(self.history_limits, display::now() * 1000);
+    }
+
+    // Wake up the scheduler when the next scheduled content is due or the
+    // displayed content expires.
+    fn reschedule(&mut self) {
+        let next = self
+            .scheduled
+            .entries()
+            .iter()
+            .filter_map(|content| content.not_before)
+            .chain(self.displayed_expires_at)
+            .min();
+        self.schedule_timer = match next {
+            Some(t) => {
+                // Content may be scheduled far ahead; the scheduler then just
+                // wakes up in between.
+                let delay = cmp::min(t.saturating_sub(display::now()), MAX_SCHEDULE_DELAY);
+                Delay::new(time::Duration::from_secs(delay)).fuse()
+            }
+            None => Fuse::terminated(),
+        };
+    }
+
+    // Receive data from the network.
+    //
+    // Messages are only handled if they are correctly signed and were not
+    // received before. Authorization is based on the author that signed the
+    // message, not the peer that sent it to us.
+    pub async fn network_receive(&mut self, sender: String, data: &[u8]) {
+        let (author, sequence, mut decoded) = match self.open(data) {
+            Ok(opened) => opened,
+            // Peers that run another version, e.g. during a rolling upgrade,
+            // may still send retired types or already send new ones.
+            Err(InvalidMessage::UnknownType(message_type)) => {
+                println!(
+                    "[Management] Ignoring message of unknown type {} from {:?}",
+                    message_type, sender
+                );
+                return;
+            }
+            Err(reason) => {
+                self.drop_invalid(sender, reason).await;
+                return;
+            }
+        };
+        if !self.replay_guard.check(&author, sequence) {
+            println!("[Management] Dropping replayed message from {:?}", author);
+            return;
+        }
+        if author != sender {
+            println!(
+                "[Management] Got message by {:?} through {:?}",
+                self.name_of(&author),
+                self.name_of(&sender)
+            );
+        }
+
+        // The version of stored content is only taken from the dht, see
+        // `show_stored_content`, and never from messages of other peers.
+        decoded.message = None;
+        self._handle_message(author, decoded, sequence, Some(data))
+            .await;
+    }
+
+    // Wrap a ControlMessage in a `SignedMessage` envelope, signed with our identity.
+    fn seal(&mut self, msg: &ControlMessage) -> Vec<u8> {
+        // The sequence number is based on the system time, so that it keeps
+        // increasing across restarts.
+        self.sequence = cmp::max(self.sequence + 1, now_millis());
+
+        let message = msg.encode_to_vec();
+        let signature = self
+            .network
+            .sign(&auth::signed_data(self.sequence, &message));
+        SignedMessage {
+            public_key: self.network.public_key(),
+            sequence: self.sequence,
+            message,
+            signature,
+        }
+        .encode_to_vec()
+    }
+
+    // Verify and unwrap a `SignedMessage` envelope.
+    // Returns the author, sequence number and message if the signature and
+    // the message are valid.
+    fn open(&self, data: &[u8]) -> Result<(String, u64, ControlMessage), InvalidMessage> {
+        let signed = SignedMessage::decode(Bytes::copy_from_slice(data))
+            .map_err(|_| InvalidMessage::Undecodable)?;
+        let author = p2p_network::verify_signature(
+            &signed.public_key,
+            &auth::signed_data(signed.sequence, &signed.message),
+            &signed.signature,
+        )
+        .ok_or(InvalidMessage::InvalidSignature)?;
+        let msg = ControlMessage::decode(Bytes::from(signed.message))
+            .map_err(|_| InvalidMessage::Undecodable)?;
+        msg.validate()?;
+        Ok((author, signed.sequence, msg))
+    }
+
+    // Unwrap the content of a StoreMessage.
+    // Its version must match the content and the signed original display
+    // message, so that peers can not pass off old content as new.
+    fn stored_content(&self, message: StoreMessage) -> Option<Content> {
+        let version = stored_version(&message);
+        if version.sha256 != display::content_digest(&message.data) {
+            return None;
+        }
+        let (author, sequence, original) = self.open(&message.envelope).ok()?;
+        let matches = author == version.author
+            && sequence == version.timestamp
+            && original.message_type == MessageType::DisplayMessage as i32
+            && original.payload == message.data;
+        if !matches {
+            return None;
+        }
+        Some(Content {
+            data: message.data,
+            envelope: message.envelope,
+            version,
+            not_before: original.not_before,
+            expires_at: original.expires_at,
+        })
+    }
+
+    // Sign a ControlMessage and send it to the network layer.
+    pub async fn send(&mut self, msg: ControlMessage, target: Option<String>) {
+        let message_type = MessageType::from_i32(msg.message_type).unwrap();
+        let sealed = self.seal(&msg);
+        let _ = self.send_sealed(message_type, sealed, target).await;
+    }
+
+    // Broadcast one of our messages that changes the network state, and apply
+    // it ourselves with the same timestamp as all other peers.
+    async fn broadcast_and_apply(&mut self, msg: ControlMessage) {
+        let message_type = MessageType::from_i32(msg.message_type).unwrap();
+        let sealed = self.seal(&msg);
+        let timestamp = self.sequence;
+        // Broadcast first, so that a revoked peer is still connected and the
+        // message reaches the whole network.
+        let _ = self.send_sealed(message_type, sealed.clone(), None).await;
+        self._handle_message(self.local_id.clone(), msg, timestamp, Some(&sealed))
+            .await;
+    }
+
+    // Send an already signed message to the network layer.
+    // Returns the delivery of a direct message; broadcast messages are not
+    // acknowledged, so their delivery resolves immediately.
+    async fn send_sealed(
+        &mut self,
+        message_type: MessageType,
+        sealed: Vec<u8>,
+        target: Option<String>,
+    ) -> Result<Delivery, NetworkError> {
+        println!(
+            "[Management] Sending message of type {:?} to {:?}",
+            message_type,
+            target
+                .as_deref()
+                .map_or("broadcast".into(), |t| self.name_of(t))
+        );
+
+        let result = match target {
+            Some(t) => self.network.send_message(t, sealed).await,
+            None => self
+                .network
+                .publish_message(sealed)
+                .await
+                .map(|_| future::ready(Ok(())).boxed()),
+        };
+        if let Err(e) = result.as_ref() {
+            println!(
+                "[Management] Could not send message of type {:?}: {}",
+                message_type, e
+            );
+        }
+        result
+    }
+
+    // Resolve a peer that the user gave by its alias or its peer id.
+    // Fails if the name is ambiguous, i.e. it is the alias of one peer and the
+    // id of another.
+    fn resolve_peer(&self, name: &str) -> Result<String, String> {
+        let owner = match self.alias_owners().remove(name) {
+            Some(owner) => owner,
+            None => return Ok(name.to_string()),
+        };
+        let is_peer_id = owner != name
+            && (self.members.get_entry(name).is_some()
+                || self.aliases.get_entry(name).is_some()
+                || self.connected_peers.iter().any(|p| p == name));
+        if is_peer_id {
+            return Err(format!(
+                "Name {:?} is ambiguous, it is a peer id and the alias of {:?}",
+                name, owner
+            ));
+        }
+        Ok(owner)
+    }
+
+    // Name of a peer for output: its alias if it owns one that is
+    // unambiguous, otherwise its id.
+    fn name_of(&self, peer: &str) -> String {
+        match self.aliases.get(peer) {
+            Some(claim) if self.resolve_peer(&claim.alias).as_deref() == Ok(peer) => {
+                claim.alias.clone()
+            }
+            _ => peer.to_string(),
+        }
+    }
+
+    fn names_of(&self, peers: &[String]) -> Vec<String> {
+        peers.iter().map(|peer| self.name_of(peer)).collect()
+    }
+
+    // Handle a message of `sender`.
+    // The timestamp is the sequence number of the message, i.e. the time in
+    // milliseconds at which it was sent. The signed envelope of the message is
+    // present for messages that were received from the network and for our
+    // own broadcasts of state changes.
+    async fn _handle_message(
+        &mut self,
+        sender: String,
+        msg: ControlMessage,
+        timestamp: u64,
+        envelope: Option<&[u8]>,
+    ) {
+        println!(
+            "[Management] Got message of type {:?} from {:?}",
+            MessageType::from_i32(msg.message_type),
+            self.name_of(&sender),
+        );
+
+        // return if the role of the sender does not permit this message
+        if !self.is_permitted(&sender, &msg) {
+            println!("[Management] Unauthorized sender: {:?}", msg);
+            return;
+        }
+
+        match MessageType::from_i32(msg.message_type) {
+            Some(MessageType::DisplayMessage) => {
+                // Stored content that we requested carries its version, which
+                // may be older than what we display already.
+                let is_stored = msg.message.is_some();
+                let version = match &msg.message {
+                    Some(stored) => stored_version(stored),
+                    None => Version::new(timestamp, sender.clone(), &msg.payload),
+                };
+                let content = Content {
+                    data: msg.payload,
+                    envelope: envelope.map(<[u8]>::to_vec).unwrap_or_default(),
+                    version,
+                    not_before: msg.not_before,
+                    expires_at: msg.expires_at,
+                };
+                let now = display::now();
+                if content.is_expired(now) {
+                    println!(
+                        "[Management] Ignoring expired content from {:?}",
+                        self.name_of(&sender)
+                    );
                     return;
                 }
-                if let Some((target, republish)) = self.dht.on_peer_disconnect(&msg.payload) {
+                if envelope.is_some() {
+                    self.history.insert(content.clone());
+                    self.history.prune(self.history_limits, now * 1000);
+                }
+                if !content.has_started(now) {
                     println!(
-                        "[Management] Republishing data to {:?}: {:?}",
-                        target, republish
+                        "[Management] Scheduling content from {:?}",
+                        self.name_of(&sender)
                     );
-                    for (peer, data) in republish {
-                        self.send(
-                            ControlMessage {
-                                message_type: MessageType::StoreMessage as i32,
-                                state: None,
-                                message: Some(StoreMessage {
-                                    receiver: Some(peer),
-                                    data,
-                                }),
-                                payload: String::new(),
-                            },
-                            Some(target.clone()),
-                        )
-                        .await;
-                    }
+                    self.schedule(content);
+                    self.reschedule();
+                    return;
+                }
+                if is_stored && self.displayed_version.as_ref() >= Some(&content.version) {
+                    println!(
+                        "[Management] Ignoring outdated stored content from {:?}",
+                        self.name_of(&sender)
+                    );
+                    return;
                 }
+                self.render(content).await;
             }
-            Some(MessageType::RequestMessage) => {
-                if let Some(message) = self.dht.get_content(&sender) {
-                    self.send(
-                        ControlMessage::new(MessageType::DisplayMessage, message),
-                        Some(sender),
-                    )
-                    .await;
+            Some(MessageType::DisplayResult) => {
+                let result = match msg.display_result {
+                    Some(r) => r,
+                    None => return,
+                };
+                if result.return_code == display::NOT_RENDERED {
+                    println!("[Management] {} has no display to render on", sender);
+                } else if result.return_code != 0 {
+                    println!(
+                        "[Management] Display of {} failed with code {}",
+                        sender, result.return_code
+                    );
+                }
+                let status = DisplayStatus {
+                    content: self.sent_content.get(&result.content_sha256),
+                    content_sha256: result
+                        .content_sha256
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect(),
+                    return_code: result.return_code,
+                    timestamp: result.timestamp,
+                };
+                self.display_status.insert(sender, status);
+            }
+            Some(MessageType::GetDisplayContent) => {
+                let mut ctrl = ControlMessage::new(MessageType::DisplayContent, "");
+                ctrl.display_content = self.displayed.clone();
+                self.send(ctrl, Some(sender)).await;
+            }
+            Some(MessageType::DisplayContent) => {
+                // Only accept answers to our own requests.
+                let requests = match self.display_content_requests.remove(&sender) {
+                    Some(r) => r,
+                    None => return,
+                };
+                for tx in requests {
+                    let _ = tx.send(msg.display_content.clone());
                 }
             }
-            Some(MessageType::StoreMessage) => {
-                let message = match msg.message {
-                    Some(m) => m,
+            Some(MessageType::AddWhitelistPeer) | Some(MessageType::RemoveWhitelistPeer) => {
+                // The signed message is kept, so that the change can be
+                // relayed with the network state.
+                let envelope = match envelope {
+                    Some(e) => e.to_vec(),
                     None => return,
                 };
-                match message.receiver {
-                    Some(r) => {
-                        println!("[Management] Persisting content for  {}", r);
-                        self.dht.store(r, message.data)
+                let entry = Entry {
+                    value: envelope,
+                    timestamp,
+                    author: sender,
+                    removed: msg.message_type == MessageType::RemoveWhitelistPeer as i32,
+                };
+                self.update_member(msg.payload, entry).await;
+            }
+            Some(MessageType::AssignRole) => {
+                let (assignment, envelope) = match (msg.role_assignment, envelope) {
+                    (Some(a), Some(e)) => (a, e.to_vec()),
+                    _ => return,
+                };
+                let grant = RoleGrant {
+                    role: Role::from_i32(assignment.role).unwrap_or(Role::Viewer),
+                    envelope,
+                };
+                let entry = Entry {
+                    value: grant,
+                    timestamp,
+                    author: sender,
+                    removed: false,
+                };
+                self.update_role(assignment.peer, entry);
+            }
+            Some(MessageType::PublishAlias) | Some(MessageType::UnpublishAlias) => {
+                let envelope = match envelope {
+                    Some(e) => e.to_vec(),
+                    None => return,
+                };
+                let removed = msg.message_type == MessageType::UnpublishAlias as i32;
+                let claim = AliasClaim {
+                    alias: if removed { String::new() } else { msg.payload },
+                    envelope,
+                };
+                // The sender can only claim an alias for itself, and replaces
+                // its previous alias.
+                let entry = Entry {
+                    value: claim,
+                    timestamp,
+                    author: sender.clone(),
+                    removed,
+                };
+                self.update_alias(sender, entry);
+            }
+            Some(MessageType::NetworkSolicitation) => {
+                if msg.payload == self.state_digest() {
+                    // The requester already has the same state.
+                    return;
+                }
+                let mut ctrl = ControlMessage::new(MessageType::State, "");
+                ctrl.state = Some(self.network_state());
+                self.send(ctrl, Some(sender)).await;
+            }
+            Some(MessageType::Upgrade) => {
+                println!("[Management] Got upgrade from {}", self.name_of(&sender));
+                let release = match (msg.release, self.release_key.as_deref()) {
+                    (Some(release), Some(release_key)) => {
+                        if UpgradeServer::verify_release(&release, release_key).is_err() {
+                            self.upgrade_in_progress = false;
+                            return;
+                        }
+                        // Upgrades that were requested with `upgrade self` are
+                        // installed regardless of the upgrade policy.
+                        let requested = self.requested_upgrade.as_ref() == Some(&sender);
+                        if requested {
+                            self.requested_upgrade = None;
+                        }
+                        if !requested
+                            && self.decide_upgrade(&sender, &release.version)
+                                != UpgradeDecision::Upgrade
+                        {
+                            println!(
+                                "[Management] Rejecting upgrade to {} by upgrade policy",
+                                release.version
+                            );
+                            self.upgrade_in_progress = false;
+                            return;
+                        }
+                        release
+                    }
+                    (None, _) => {
+                        println!("[Management] Rejecting upgrade without signed release");
+                        self.upgrade_in_progress = false;
+                        return;
+                    }
+                    (_, None) => {
+                        println!("[Management] Rejecting upgrade, no release key configured");
+                        self.upgrade_in_progress = false;
+                        return;
                     }
+                };
+                self.download_upgrade(sender, msg.payload, release).await;
+            }
+            Some(MessageType::RequestUpgrade) => {
+                println!(
+                    "[Management] Got upgrade request from {}",
+                    self.name_of(&sender)
+                );
+                if self.upgrade_in_progress {
+                    return;
+                }
+
+                // Offer the binary that we serve if it was requested, which is
+                // already provided.
+                if msg.payload == upgrade::SERVED_BINARY_KEY {
+                    if let Some(release) = self.upgrader.release() {
+                        let mut ctrl =
+                            ControlMessage::new(MessageType::Upgrade, upgrade::SERVED_BINARY_KEY);
+                        ctrl.release = Some(release);
+                        self.send(ctrl, Some(sender)).await;
+                        return;
+                    }
+                }
+
+                let current_path = UpgradeServer::get_current_path();
+                let mut ctrl =
+                    ControlMessage::new(MessageType::Upgrade, upgrade::CURRENT_BINARY_KEY);
+                ctrl.release = upgrade::read_manifest(&current_path);
+                let result = self
+                    .network
+                    .provide_file(upgrade::CURRENT_BINARY_KEY.into(), current_path)
+                    .await;
+                if let Err(e) = result {
+                    println!("[Management] Could not serve our binary: {}", e);
+                    return;
+                }
+                self.send(ctrl, Some(sender)).await;
+            }
+            Some(MessageType::NetworkBinaryVersion) => {
+                println!(
+                    "[Management] Got binary version from {}",
+                    self.name_of(&sender)
+                );
+                let build = match msg.binary_version {
+                    Some(build) if version::is_compatible(&build) => build,
+                    _ => {
+                        println!("[Management] Ignoring incompatible build from {}", sender);
+                        return;
+                    }
+                };
+                let announced = match version::parse_version(&build.version) {
+                    Some(v) => v,
                     None => {
-                        println!("[Management] Persisting broadcasted content",);
-                        self.dht.store_broadcast_content(message.data)
+                        println!(
+                            "[Management] Ignoring invalid version {:?} from {}",
+                            build.version, sender
+                        );
+                        return;
+                    }
+                };
+                let current = version::CURRENT_VERSION.and_then(version::parse_version);
+                match self
+                    .upgrade_policy
+                    .decide(current.as_ref(), &announced, &sender)
+                {
+                    UpgradeDecision::Ignore => return,
+                    UpgradeDecision::Notify => {
+                        println!(
+                            "[Management] Version {} is available from {}",
+                            announced, sender
+                        );
+                        return;
                     }
+                    UpgradeDecision::Upgrade => {}
                 }
+                if self.upgrade_in_progress {
+                    return;
+                }
+                self.upgrade_in_progress = true;
+
+                self.send(
+                    ControlMessage::new(MessageType::RequestUpgrade, ""),
+                    Some(sender),
+                )
+                .await;
             }
             Some(MessageType::State) => {
                 println!(
                     "[Management] Got network state from {}: {:?}",
                     sender, msg.state
                 );
-                let state = msg.state.unwrap();
-                for peer in state.connected {
-                    self.dht.add_peer(peer);
-                }
-                for peer in state.whitelisted {
-                    self.network.add_whitelisted(peer).await;
+                let state = match msg.state {
+                    Some(state) => state,
+                    None => return,
+                };
+                // Entries are adopted from anyone, since each entry is signed
+                // by an admin or, for aliases, by its peer.
+                // Admins can be appointed by other admins, so role entries are
+                // applied until no more of them can be verified.
+                let mut roles = state.roles;
+                loop {
+                    let count = roles.len();
+                    let mut unverified = Vec::new();
+                    for entry in roles {
+                        match self.open_role_entry(entry.clone()) {
+                            Some((peer, entry)) => self.update_role(peer, entry),
+                            None => unverified.push(entry),
+                        }
+                    }
+                    roles = unverified;
+                    if roles.len() == count {
+                        break;
+                    }
                 }
-                for peer in state.whitelisted_sender {
-                    self.authorized_senders.push(peer)
+                if !roles.is_empty() {
+                    println!(
+                        "[Management] Ignoring {} role entries without a valid signature of an admin",
+                        roles.len()
+                    );
                 }
-                for Alias { peer, alias } in state.aliases {
-                    self.aliases.insert(peer, alias);
+                for entry in state.members {
+                    match self.open_member_entry(entry) {
+                        Some((peer, entry)) => self.update_member(peer, entry).await,
+                        None => println!(
+                            "[Management] Ignoring whitelist entry without a valid signature of an admin"
+                        ),
+                    }
                 }
-                if let Some(closest) = self
-                    .dht
-                    .get_closest_peers(&self.local_id)
-                    .into_iter()
-                    .next()
-                {
-                    if closest != self.local_id {
-                        self.send(
-                            ControlMessage::new(MessageType::RequestMessage, ""),
-                            Some(closest),
-                        )
-                        .await;
+                for entry in state.aliases {
+                    match self.open_alias_entry(entry) {
+                        Some((peer, entry)) => self.update_alias(peer, entry),
+                        None => {
+                            println!("[Management] Ignoring alias entry without a valid signature")
+                        }
                     }
                 }
+                // Stored messages are only requested once, when joining the
+                // network, and not on every later sync of the state.
+                if !std::mem::take(&mut self.joining) || !self.request_on_join {
+                    return;
+                }
+                self.request_stored_content().await;
             }
             None => {
                 println!("Could not parse message");
@@ -615,17 +2284,90 @@ impl<T: NetworkLayer> Management<T> {
     }
 }
 
+// Current unix time in milliseconds.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Whether a timestamp of the replicated state lies too far in the future.
+// Such an entry would win over all later changes, so it is ignored until its
+// time has come.
+fn is_from_future(timestamp: u64) -> bool {
+    timestamp > now_millis().saturating_add(auth::MAX_CLOCK_SKEW)
+}
+
+// Convert the entries of a replicated map to their protobuf representation,
+// in which `set_value` stores the value and its signed message. Entries
+// without a signed message, e.g. the peers of our own whitelist, can not be
+// verified by others and are left out.
+fn state_entries<V, F>(map: &LwwMap<V>, set_value: F) -> Vec<StateEntry>
+where
+    V: Clone + Default,
+    F: Fn(&mut StateEntry, &V),
+{
+    map.entries()
+        .map(|(peer, entry)| {
+            let mut state_entry = StateEntry {
+                peer: peer.clone(),
+                timestamp: entry.timestamp,
+                author: entry.author.clone(),
+                removed: entry.removed,
+                ..Default::default()
+            };
+            set_value(&mut state_entry, &entry.value);
+            state_entry
+        })
+        .filter(|entry| entry.envelope.as_ref().map_or(false, |e| !e.is_empty()))
+        .collect()
+}
+
+// Content with its version, as it is exchanged between peers.
+fn store_message(receiver: Option<String>, content: Content) -> StoreMessage {
+    StoreMessage {
+        receiver,
+        data: content.data,
+        envelope: content.envelope,
+        timestamp: content.version.timestamp,
+        author: content.version.author,
+        content_sha256: content.version.sha256,
+    }
+}
+
+fn stored_history(receiver: Option<String>, history: &History) -> StoredHistory {
+    StoredHistory {
+        messages: history
+            .entries()
+            .iter()
+            .map(|content| store_message(receiver.clone(), content.clone()))
+            .collect(),
+    }
+}
+
+fn stored_version(message: &StoreMessage) -> Version {
+    Version {
+        timestamp: message.timestamp,
+        author: message.author.clone(),
+        sha256: message.content_sha256.clone(),
+    }
+}
+
+// Render data on the display. Returns the return code of the display driver,
+// or `NOT_RENDERED` without a display.
 #[cfg(feature = "display")]
-fn write_to_display(mut data: String) {
+fn write_to_display(mut data: String) -> i32 {
     println!("[DISPLAY] Sending data to display: {:?}", data);
     unsafe {
         data = data.replace(|c: char| !c.is_ascii(), "");
         data.push('\0');
-        toDisplay(data.as_mut_ptr().cast());
+        toDisplay(data.as_mut_ptr().cast())
     }
 }
 
 #[cfg(not(feature = "display"))]
-fn write_to_display(data: String) {
+fn write_to_display(data: String) -> i32 {
     println!("[DISPLAY] MOCK sending data to display: {:?}", data);
+    display::NOT_RENDERED
 }
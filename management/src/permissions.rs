@@ -0,0 +1,40 @@
+use crate::protocol::{MessageType, Role};
+
+// Role that an admin assigned to a peer.
+//
+// Like an alias claim, the grant keeps the signed ASSIGN_ROLE message by which
+// it was made, so that every peer can verify it no matter who relayed it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoleGrant {
+    pub role: Role,
+    // Signed message of the admin, empty for roles that were assigned before
+    // grants were signed.
+    pub envelope: Vec<u8>,
+}
+
+// Whether a peer with the given role is permitted to send a message of the
+// given type.
+//
+// Peers without an assigned role are viewers. They may only send the messages
+// that every node needs for participating in the network.
+pub fn is_permitted(role: Role, message_type: MessageType) -> bool {
+    match message_type {
+        MessageType::PublishAlias
+        | MessageType::UnpublishAlias
+        | MessageType::NetworkSolicitation
+        | MessageType::NetworkBinaryVersion
+        | MessageType::RequestUpgrade
+        | MessageType::State
+        | MessageType::DisplayResult
+        | MessageType::DisplayContent => true,
+        // Any peer may answer an upgrade request, since releases are only
+        // installed if they are signed with the release key.
+        MessageType::Upgrade => true,
+        MessageType::DisplayMessage | MessageType::GetDisplayContent => {
+            matches!(role, Role::Publisher | Role::Admin)
+        }
+        MessageType::AddWhitelistPeer
+        | MessageType::RemoveWhitelistPeer
+        | MessageType::AssignRole => role == Role::Admin,
+    }
+}
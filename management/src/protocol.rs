@@ -1,8 +1,18 @@
-pub use message::control_message::Alias;
+use std::fmt;
+
+pub use message::control_message::DisplayContent;
+pub use message::control_message::DisplayResult;
 pub use message::control_message::MessageType;
 pub use message::control_message::NetworkState;
+pub use message::control_message::Role;
+pub use message::control_message::RoleAssignment;
+pub use message::control_message::StateEntry;
 pub use message::control_message::StoreMessage;
+pub use message::control_message::StoredHistory;
+pub use message::BinaryVersion;
 pub use message::ControlMessage;
+pub use message::Release;
+pub use message::SignedMessage;
 
 mod message {
     include!(concat!(env!("OUT_DIR"), "/management.control_message.rs"));
@@ -15,6 +25,68 @@ impl ControlMessage {
             payload: payload.into(),
             state: None,
             message: None,
+            role_assignment: None,
+            release: None,
+            binary_version: None,
+            display_result: None,
+            display_content: None,
+            not_before: None,
+            expires_at: None,
+        }
+    }
+}
+
+// Reason why an inbound message was dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMessage {
+    // The data is not a valid protobuf encoded message.
+    Undecodable,
+    // The signature of the envelope does not match its content.
+    InvalidSignature,
+    // The type is unknown or retired. Such messages are ignored, but not
+    // counted as invalid, since they are sent by peers of other versions.
+    UnknownType(i32),
+    // A field that the message type requires is not set.
+    MissingField(&'static str),
+}
+
+impl fmt::Display for InvalidMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidMessage::Undecodable => write!(f, "undecodable message"),
+            InvalidMessage::InvalidSignature => write!(f, "invalid signature"),
+            InvalidMessage::UnknownType(t) => write!(f, "unknown message type {}", t),
+            InvalidMessage::MissingField(field) => write!(f, "missing field {:?}", field),
+        }
+    }
+}
+
+impl ControlMessage {
+    // Check that the message has a known type and carries all fields that its
+    // type requires, so that handlers can rely on them.
+    pub fn validate(&self) -> Result<MessageType, InvalidMessage> {
+        let message_type = MessageType::from_i32(self.message_type)
+            .ok_or(InvalidMessage::UnknownType(self.message_type))?;
+        let missing = match message_type {
+            MessageType::State if self.state.is_none() => Some("state"),
+            MessageType::AssignRole if self.role_assignment.is_none() => Some("role_assignment"),
+            MessageType::DisplayResult if self.display_result.is_none() => Some("display_result"),
+            MessageType::NetworkBinaryVersion if self.binary_version.is_none() => {
+                Some("binary_version")
+            }
+            MessageType::AddWhitelistPeer
+            | MessageType::RemoveWhitelistPeer
+            | MessageType::PublishAlias
+            | MessageType::Upgrade
+                if self.payload.is_empty() =>
+            {
+                Some("payload")
+            }
+            _ => None,
+        };
+        match missing {
+            Some(field) => Err(InvalidMessage::MissingField(field)),
+            None => Ok(message_type),
         }
     }
 }
@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::alias::AliasClaim;
+use crate::crdt::LwwMap;
+use crate::permissions::RoleGrant;
+use crate::protocol::Role;
+
+// Version of the snapshot format. Increase it on incompatible changes and
+// convert snapshots of the previous version in `open_snapshot`.
+const STATE_VERSION: u64 = 1;
+
+// State of the management that survives a restart.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    // Our own alias.
+    pub alias: String,
+    // Replicated network state, see `Management`. Members are stored as the
+    // signed message of their whitelist entry in base64.
+    pub members: LwwMap<String>,
+    pub roles: LwwMap<StoredRole>,
+    pub aliases: LwwMap<StoredAlias>,
+    // Content that we stored in the dht, as one base64 encoded
+    // `StoredHistory` per receiver.
+    pub stored_history: Vec<String>,
+    // Records that other peers stored at us, by key.
+    pub records: BTreeMap<String, StoredRecord>,
+    // Highest sequence number of the messages received from each author, so
+    // that they can not be replayed after a restart.
+    pub sequences: BTreeMap<String, u64>,
+}
+
+// Alias claim in the snapshot, with the signed envelope in base64.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredAlias {
+    pub alias: String,
+    pub envelope: String,
+}
+
+impl From<&AliasClaim> for StoredAlias {
+    fn from(claim: &AliasClaim) -> Self {
+        StoredAlias {
+            alias: claim.alias.clone(),
+            envelope: base64::encode(&claim.envelope),
+        }
+    }
+}
+
+impl From<&StoredAlias> for AliasClaim {
+    fn from(claim: &StoredAlias) -> Self {
+        AliasClaim {
+            alias: claim.alias.clone(),
+            // Other peers ignore a claim with an invalid envelope, until its
+            // peer publishes the alias again.
+            envelope: base64::decode(&claim.envelope).unwrap_or_default(),
+        }
+    }
+}
+
+// Role grant in the snapshot, with the protobuf `Role` value and the signed
+// envelope in base64.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredRole {
+    pub role: i32,
+    pub envelope: String,
+}
+
+impl From<&RoleGrant> for StoredRole {
+    fn from(grant: &RoleGrant) -> Self {
+        StoredRole {
+            role: grant.role as i32,
+            envelope: base64::encode(&grant.envelope),
+        }
+    }
+}
+
+impl From<&StoredRole> for RoleGrant {
+    fn from(grant: &StoredRole) -> Self {
+        RoleGrant {
+            role: Role::from_i32(grant.role).unwrap_or(Role::Viewer),
+            envelope: base64::decode(&grant.envelope).unwrap_or_default(),
+        }
+    }
+}
+
+// Record in the snapshot, with the value in base64 and the unix time in
+// seconds at which it expires.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredRecord {
+    pub value: String,
+    pub expires: Option<u64>,
+}
+
+// On-disk store for the `State`.
+//
+// The state is saved as a versioned JSON snapshot:
+// ```text
+// {"version": 1, "state": {...}}
+// ```
+// Nodes that ran before the state was persisted have no snapshot and start
+// with the default state.
+pub struct StateStore {
+    path: PathBuf,
+    // Last saved snapshot, to only write the file if the state changed.
+    last_saved: Option<String>,
+}
+
+impl StateStore {
+    pub fn new(path: &Path) -> Self {
+        StateStore {
+            path: path.into(),
+            last_saved: None,
+        }
+    }
+
+    // Load the state from disk.
+    // Returns the default state if the file does not exist yet. A snapshot
+    // that can not be read is moved aside, so that it is not overwritten.
+    pub fn load(&mut self) -> io::Result<State> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => return Err(e),
+        };
+        let state = serde_json::from_str(&content)
+            .map_err(|e| e.to_string())
+            .and_then(open_snapshot)
+            .and_then(|state| serde_json::from_value(state).map_err(|e| e.to_string()));
+        match state {
+            Ok(state) => {
+                self.last_saved = Some(content);
+                Ok(state)
+            }
+            Err(e) => {
+                let backup = self.path.with_extension("bak");
+                println!(
+                    "[Management] Invalid state snapshot, moving it to {:?}: {}",
+                    backup, e
+                );
+                fs::rename(&self.path, backup)?;
+                Ok(State::default())
+            }
+        }
+    }
+
+    // Write the state to disk, if it changed since it was last saved.
+    //
+    // Like the peer store, the snapshot is first written to a temporary file
+    // that then replaces the old one. The file is synced before, so that a
+    // power loss can not leave an empty snapshot behind.
+    pub fn save(&mut self, state: &State) -> io::Result<()> {
+        let snapshot = json!({ "version": STATE_VERSION, "state": state }).to_string();
+        if self.last_saved.as_ref() == Some(&snapshot) {
+            return Ok(());
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(snapshot.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.last_saved = Some(snapshot);
+        Ok(())
+    }
+}
+
+// State in a snapshot, if the snapshot has the current version.
+fn open_snapshot(mut snapshot: Value) -> Result<Value, String> {
+    let version = snapshot
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or("snapshot has no version")?;
+    if version != STATE_VERSION {
+        return Err(format!("unsupported snapshot version {}", version));
+    }
+    Ok(snapshot["state"].take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        assert!(open_snapshot(json!({ "state": {} })).is_err());
+        assert!(open_snapshot(json!({ "version": 0, "state": {} })).is_err());
+        assert!(open_snapshot(json!({ "version": STATE_VERSION + 1, "state": {} })).is_err());
+    }
+
+    #[test]
+    fn loads_the_default_state_without_a_snapshot() {
+        let path = std::env::temp_dir().join(format!("state-test-{}.missing", std::process::id()));
+        let state = StateStore::new(&path).load().unwrap();
+        assert_eq!(state.alias, "");
+        assert_eq!(state.members.entries().count(), 0);
+        assert!(state.records.is_empty());
+    }
+
+    #[test]
+    fn saves_and_loads_the_state() {
+        let path = std::env::temp_dir().join(format!("state-test-{}.json", std::process::id()));
+        let mut state = State {
+            alias: "office".into(),
+            ..State::default()
+        };
+        state.sequences.insert("peer-a".into(), 42);
+        state
+            .members
+            .insert("peer-a".into(), "envelope".into(), 5, "admin".into());
+
+        StateStore::new(&path).save(&state).unwrap();
+        let loaded = StateStore::new(&path).load().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.alias, "office");
+        assert_eq!(loaded.sequences, state.sequences);
+        assert_eq!(
+            loaded.members.get_entry("peer-a"),
+            state.members.get_entry("peer-a")
+        );
+    }
+}
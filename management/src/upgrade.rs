@@ -1,169 +1,237 @@
 use std::{
+    fmt,
     fs::{self},
-    io::{Read, Write},
-    net::TcpStream,
-    os::unix::prelude::{OpenOptionsExt, PermissionsExt},
-    path::PathBuf,
+    io::{self, Read},
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
     process::exit,
+    time::Duration,
 };
 
-use async_std::{
-    fs::File,
-    io::{ReadExt, WriteExt},
-    net::TcpListener,
-    prelude::StreamExt,
-    task::JoinHandle,
-};
+use prost::{bytes::Bytes, Message};
+use sha2::{Digest, Sha256};
+
+use crate::protocol::Release;
 
 pub struct UpgradeServer {
-    handle: Option<JoinHandle<()>>,
+    // Release manifest of the file that is currently served.
+    release: Option<Release>,
 }
 
-pub const UPGRADE_SERVER_PORT: &str = "9803";
+// Keys under which binaries are provided to other peers via the network layer.
+// The running binary is provided to peers that request an upgrade, a binary
+// passed to `serve` is provided for upgrades triggered by the user.
+pub const CURRENT_BINARY_KEY: &str = "current-binary";
+pub const SERVED_BINARY_KEY: &str = "served-binary";
+
+// Time within which an upgraded binary has to confirm that it is healthy,
+// before the previous binary is restored.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub enum UpgradeError {
+    Io(io::Error),
+    // The digest of the downloaded binary does not match the release.
+    DigestMismatch,
+    // The release was not signed by the configured release key.
+    InvalidSignature,
+}
 
-impl UpgradeServer {
-    pub fn new() -> Self {
-        UpgradeServer { handle: None }
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpgradeError::Io(e) => write!(f, "{}", e),
+            UpgradeError::DigestMismatch => write!(f, "SHA-256 digest does not match the release"),
+            UpgradeError::InvalidSignature => write!(f, "release is not signed by the release key"),
+        }
     }
+}
 
-    pub fn get_current_path() -> PathBuf {
-        std::env::current_exe().unwrap()
+impl From<io::Error> for UpgradeError {
+    fn from(e: io::Error) -> Self {
+        UpgradeError::Io(e)
     }
+}
 
-    pub fn upgrade_binary(network_addr: String) -> Result<(), ()> {
-        let tcp_result = TcpStream::connect(&network_addr);
-        if tcp_result.is_err() {
-            println!("[UpgradeServer] Could not connect to {}", &network_addr);
-            return Err(());
-        }
-        let mut tcp = tcp_result.unwrap();
-        println!("[UpgradeServer] Upgrading from {}...", &network_addr);
-
-        let current_path = UpgradeServer::get_current_path();
-        let update_path = current_path.with_file_name(".management.update");
-
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .mode(0o777)
-            .open(&update_path)
-            .unwrap();
-        file.metadata().unwrap().permissions().set_mode(0o777);
-
-        let mut buf = [0; 4096];
-        loop {
-            let n = tcp.read(&mut buf).unwrap();
-
-            if n == 0 {
-                break;
-            }
+// Data that is signed by the release key.
+pub fn release_signed_data(version: &str, sha256: &[u8]) -> Vec<u8> {
+    let mut data = version.as_bytes().to_vec();
+    data.push(0);
+    data.extend_from_slice(sha256);
+    data
+}
 
-            let _ = file.write(&buf[..n]);
+pub fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
         }
-
-        let _ = fs::remove_file(&current_path).unwrap();
-        let _ = fs::rename(&update_path, &current_path);
-
-        println!("[UpgradeServer] Exiting to apply update...");
-
-        exit(0);
+        hasher.update(&buf[..n]);
     }
+    Ok(hasher.finalize().to_vec())
+}
 
-    pub async fn serve_binary_once(&mut self) {
-        let file_path = UpgradeServer::get_current_path();
-        let file = File::open(&file_path).await;
-
-        if file.is_err() {
-            println!("[UpgradeServer] Could not serve file {:?}", &file_path);
-            return;
-        }
-
-        let network_addr: String = String::from("0.0.0.0:") + UPGRADE_SERVER_PORT;
-        let listener = TcpListener::bind(&network_addr).await;
-        if listener.is_err() {
-            println!("[UpgradeServer] Could not listen on {}", &network_addr);
-            return;
-        }
-        println!("[UpgradeServer] Listening on {:?}...", &network_addr);
-
-        let handle = async_std::task::spawn(async {
-            let file_path = file_path;
-            let listener = listener.unwrap();
+// The release manifest of a binary is stored next to it, as `<binary>.release`.
+pub fn manifest_path(binary: &Path) -> PathBuf {
+    let mut file_name = binary.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".release");
+    binary.with_file_name(file_name)
+}
 
-            if let Ok((mut stream, _)) = listener.accept().await {
-                println!("[UpgradeServer] Serving file...");
+pub fn read_manifest(binary: &Path) -> Option<Release> {
+    let bytes = fs::read(manifest_path(binary)).ok()?;
+    Release::decode(Bytes::from(bytes)).ok()
+}
 
-                let mut file = File::open(&file_path).await.unwrap();
-                let mut buf = [0; 4096];
+pub fn write_manifest(binary: &Path, release: &Release) -> io::Result<()> {
+    fs::write(manifest_path(binary), release.encode_to_vec())
+}
 
-                loop {
-                    let n = file.read(&mut buf).await.unwrap();
+// Previous binary, that is restored if an upgrade fails.
+fn backup_path(binary: &Path) -> PathBuf {
+    binary.with_file_name(".management.backup")
+}
 
-                    if n == 0 {
-                        break;
-                    }
+// Marker for an upgrade that has not been confirmed yet.
+// Contains the number of times the upgraded binary was started; see
+// `scripts/rollback.sh`.
+fn pending_path(binary: &Path) -> PathBuf {
+    binary.with_file_name(".management.pending")
+}
 
-                    let _ = stream.write_all(&buf[..n]).await;
-                }
+impl UpgradeServer {
+    pub fn new() -> Self {
+        UpgradeServer { release: None }
+    }
 
-                let _ = stream.flush().await;
+    pub fn get_current_path() -> PathBuf {
+        std::env::current_exe().unwrap()
+    }
 
-                println!("[UpgradeServer] File served.");
-            }
-        });
-        self.handle = Some(handle);
+    // Location to which a binary is downloaded before it is installed.
+    pub fn get_update_path() -> PathBuf {
+        UpgradeServer::get_current_path().with_file_name(".management.update")
     }
 
-    pub async fn serve(&mut self, file_path: String) {
-        let file = File::open(&file_path).await;
+    // Release manifest of the file that is currently served.
+    pub fn release(&self) -> Option<Release> {
+        self.release.clone()
+    }
 
-        if file.is_err() {
-            println!("[UpgradeServer] Could not serve file {:?}", &file_path);
-            return;
+    // Check that the release is signed by the release key.
+    pub fn verify_release(release: &Release, release_key: &str) -> Result<(), UpgradeError> {
+        let signer = p2p_network::verify_signature(
+            &release.public_key,
+            &release_signed_data(&release.version, &release.sha256),
+            &release.signature,
+        );
+        if signer.as_deref() != Some(release_key) {
+            println!("[UpgradeServer] Rejecting upgrade: release has an invalid signature");
+            return Err(UpgradeError::InvalidSignature);
         }
+        Ok(())
+    }
 
-        let network_addr: String = String::from("0.0.0.0:") + UPGRADE_SERVER_PORT;
-        let listener = TcpListener::bind(&network_addr).await;
-        if listener.is_err() {
-            println!("[UpgradeServer] Could not listen on {}", &network_addr);
-            return;
+    // Replace the running executable with the binary that was downloaded to
+    // the update path.
+    //
+    // The binary is only installed if its digest matches the release, and the
+    // release is signed by the release key.
+    pub fn install_update(release: &Release, release_key: &str) -> Result<(), UpgradeError> {
+        let current_path = UpgradeServer::get_current_path();
+        let update_path = UpgradeServer::get_update_path();
+
+        let verified = UpgradeServer::verify_release(release, release_key).and_then(|_| {
+            if file_digest(&update_path)? != release.sha256 {
+                println!(
+                    "[UpgradeServer] Rejecting upgrade to {}: digest mismatch",
+                    release.version
+                );
+                return Err(UpgradeError::DigestMismatch);
+            }
+            Ok(())
+        });
+        if let Err(e) = verified {
+            let _ = fs::remove_file(&update_path);
+            return Err(e);
         }
-        println!("[UpgradeServer] Listening on {:?}...", &network_addr);
+        println!("[UpgradeServer] Verified release {}", release.version);
+        fs::set_permissions(&update_path, fs::Permissions::from_mode(0o777))?;
 
-        let handle = async_std::task::spawn(async {
-            let file_path = file_path;
-            let listener = listener.unwrap();
+        // Keep the current binary, so that it can be restored if the new one
+        // doesn't confirm its health after the restart.
+        let backup_path = backup_path(&current_path);
+        fs::copy(&current_path, &backup_path)?;
+        let _ = fs::rename(manifest_path(&current_path), manifest_path(&backup_path));
+        fs::write(pending_path(&current_path), "0")?;
 
-            let mut incoming = listener.incoming();
+        // Renaming replaces the current binary atomically.
+        fs::rename(&update_path, &current_path)?;
 
-            while let Some(Ok(mut stream)) = incoming.next().await {
-                println!("[UpgradeServer] Serving file...");
+        // Keep the manifest, so that we can serve the release to other peers.
+        let _ = write_manifest(&current_path, release);
 
-                let mut file = File::open(&file_path).await.unwrap();
-                let mut buf = [0; 4096];
-
-                loop {
-                    let n = file.read(&mut buf).await.unwrap();
+        println!("[UpgradeServer] Exiting to apply update...");
 
-                    if n == 0 {
-                        break;
-                    }
+        exit(0);
+    }
 
-                    let _ = stream.write_all(&buf[..n]).await;
-                }
+    // Whether we are running an upgraded binary that has not confirmed its
+    // health yet.
+    pub fn is_upgrade_pending() -> bool {
+        pending_path(&UpgradeServer::get_current_path()).exists()
+    }
 
-                let _ = stream.flush().await;
+    // Confirm that the upgraded binary is healthy, so that it is kept.
+    pub fn confirm_upgrade() {
+        let current_path = UpgradeServer::get_current_path();
+        match fs::remove_file(pending_path(&current_path)) {
+            Ok(()) => println!("[UpgradeServer] Confirmed upgrade"),
+            Err(e) => println!("[UpgradeServer] Could not confirm upgrade: {:?}", e),
+        }
+    }
 
-                println!("[UpgradeServer] File served.");
+    // Restore the binary from before the upgrade and exit, so that it is
+    // restarted by systemd.
+    pub fn rollback() -> ! {
+        let current_path = UpgradeServer::get_current_path();
+        let backup_path = backup_path(&current_path);
+        println!("[UpgradeServer] Rolling back to previous binary...");
+        match fs::rename(&backup_path, &current_path) {
+            Ok(()) => {
+                let _ = fs::remove_file(manifest_path(&current_path));
+                let _ = fs::rename(manifest_path(&backup_path), manifest_path(&current_path));
             }
-        });
-        self.handle = Some(handle);
+            Err(e) => println!("[UpgradeServer] Could not restore previous binary: {:?}", e),
+        }
+        let _ = fs::remove_file(pending_path(&current_path));
+        exit(1);
     }
 
-    pub async fn stop_serving(&mut self) {
-        if let Some(handle) = self.handle.take() {
-            handle.cancel().await;
-            println!("[UpgradeServer] Stopped serving.");
+    // Prepare serving the file at `file_path`.
+    // Returns `false` if the file can not be served.
+    pub fn serve(&mut self, file_path: &Path) -> bool {
+        if !file_path.is_file() {
+            println!("[UpgradeServer] Could not serve file {:?}", file_path);
+            return false;
         }
+        self.release = read_manifest(file_path);
+        if self.release.is_none() {
+            println!(
+                "[UpgradeServer] No release manifest for {:?}, peers will reject the upgrade",
+                file_path
+            );
+        }
+        println!("[UpgradeServer] Serving {:?}", file_path);
+        true
+    }
+
+    pub fn stop_serving(&mut self) {
+        self.release = None;
+        println!("[UpgradeServer] Stopped serving.");
     }
 }
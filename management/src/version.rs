@@ -0,0 +1,237 @@
+use semver::Version;
+use serde::Deserialize;
+use std::{convert::TryFrom, env::consts::ARCH, str::FromStr};
+
+use crate::protocol::BinaryVersion;
+
+// Version of this build, set at compile time.
+pub const CURRENT_VERSION: Option<&str> = option_env!("DF_VERSION");
+
+// Parse a semantic version, optionally prefixed with "v".
+pub fn parse_version(version: &str) -> Option<Version> {
+    Version::parse(version.trim().trim_start_matches('v')).ok()
+}
+
+// The build that we are running, as it is announced to other peers.
+// `None` if the build has no version.
+pub fn local_build() -> Option<BinaryVersion> {
+    CURRENT_VERSION.map(|version| BinaryVersion {
+        version: version.into(),
+        arch: ARCH.into(),
+        features: local_features(),
+    })
+}
+
+fn local_features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(feature = "display") {
+        features.push("display".into());
+    }
+    features
+}
+
+// Whether a build announced by another peer can replace our binary, i.e. it
+// was built for the same architecture and with the same features.
+pub fn is_compatible(build: &BinaryVersion) -> bool {
+    let mut features = build.features.clone();
+    features.sort();
+    build.arch == ARCH && features == local_features()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum UpgradeMode {
+    // Upgrade to any newer version that is announced.
+    Auto,
+    // Only report newer versions, upgrades have to be triggered manually.
+    NotifyOnly,
+    // Only upgrade to exactly this version.
+    Pinned(Version),
+}
+
+impl FromStr for UpgradeMode {
+    type Err = String;
+
+    // Parses "auto", "notify" or "pinned=<version>".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(UpgradeMode::Auto),
+            "notify" => Ok(UpgradeMode::NotifyOnly),
+            _ => s
+                .strip_prefix("pinned=")
+                .and_then(parse_version)
+                .map(UpgradeMode::Pinned)
+                .ok_or_else(|| format!("invalid upgrade policy {:?}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for UpgradeMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpgradeDecision {
+    Ignore,
+    Notify,
+    Upgrade,
+}
+
+// Decides whether we upgrade to versions that other peers announce.
+#[derive(Debug, Clone)]
+pub struct UpgradePolicy {
+    pub mode: UpgradeMode,
+    // Peers from which upgrades are accepted. If empty, upgrades from all
+    // peers are accepted.
+    pub allowed_sources: Vec<String>,
+}
+
+impl Default for UpgradePolicy {
+    fn default() -> Self {
+        UpgradePolicy {
+            mode: UpgradeMode::Auto,
+            allowed_sources: Vec::new(),
+        }
+    }
+}
+
+impl UpgradePolicy {
+    fn is_allowed_source(&self, peer: &str) -> bool {
+        self.allowed_sources.is_empty() || self.allowed_sources.iter().any(|p| p == peer)
+    }
+
+    // What to do about a version that `source` announced.
+    // `current` is `None` if our own build has no version.
+    pub fn decide(
+        &self,
+        current: Option<&Version>,
+        announced: &Version,
+        source: &str,
+    ) -> UpgradeDecision {
+        if !self.is_allowed_source(source) {
+            return UpgradeDecision::Ignore;
+        }
+        match &self.mode {
+            UpgradeMode::Pinned(pin) if announced == pin && current != Some(pin) => {
+                UpgradeDecision::Upgrade
+            }
+            UpgradeMode::Pinned(_) => UpgradeDecision::Ignore,
+            _ if current.map_or(false, |current| current >= announced) => UpgradeDecision::Ignore,
+            UpgradeMode::NotifyOnly => UpgradeDecision::Notify,
+            UpgradeMode::Auto => UpgradeDecision::Upgrade,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str) -> Version {
+        parse_version(version).unwrap()
+    }
+
+    fn policy(mode: UpgradeMode) -> UpgradePolicy {
+        UpgradePolicy {
+            mode,
+            allowed_sources: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_versions_with_prefix() {
+        assert_eq!(parse_version("v1.2.3"), Some(Version::new(1, 2, 3)));
+        assert_eq!(parse_version(" 1.2.3\n"), Some(Version::new(1, 2, 3)));
+        assert_eq!(parse_version("1.2"), None);
+    }
+
+    #[test]
+    fn parses_upgrade_modes() {
+        assert_eq!("auto".parse::<UpgradeMode>(), Ok(UpgradeMode::Auto));
+        assert_eq!("notify".parse::<UpgradeMode>(), Ok(UpgradeMode::NotifyOnly));
+        assert_eq!(
+            "pinned=v0.2.0".parse::<UpgradeMode>(),
+            Ok(UpgradeMode::Pinned(version("0.2.0")))
+        );
+        assert!("pinned=".parse::<UpgradeMode>().is_err());
+        assert!("always".parse::<UpgradeMode>().is_err());
+    }
+
+    #[test]
+    fn auto_upgrades_to_newer_versions_only() {
+        let policy = policy(UpgradeMode::Auto);
+        let current = version("0.9.0");
+        // Versions are compared semantically, not as strings.
+        assert_eq!(
+            policy.decide(Some(&current), &version("0.10.0"), "peer"),
+            UpgradeDecision::Upgrade
+        );
+        assert_eq!(
+            policy.decide(Some(&version("0.10.0")), &current, "peer"),
+            UpgradeDecision::Ignore
+        );
+        assert_eq!(
+            policy.decide(Some(&current), &current, "peer"),
+            UpgradeDecision::Ignore
+        );
+        assert_eq!(
+            policy.decide(None, &current, "peer"),
+            UpgradeDecision::Upgrade
+        );
+    }
+
+    #[test]
+    fn notify_only_reports_newer_versions() {
+        let policy = policy(UpgradeMode::NotifyOnly);
+        let current = version("0.9.0");
+        assert_eq!(
+            policy.decide(Some(&current), &version("0.10.0"), "peer"),
+            UpgradeDecision::Notify
+        );
+        assert_eq!(
+            policy.decide(Some(&current), &version("0.8.0"), "peer"),
+            UpgradeDecision::Ignore
+        );
+    }
+
+    #[test]
+    fn pinned_upgrades_to_the_pinned_version_only() {
+        let pin = version("0.9.0");
+        let policy = policy(UpgradeMode::Pinned(pin.clone()));
+        // Downgrades to the pinned version are allowed.
+        assert_eq!(
+            policy.decide(Some(&version("0.10.0")), &pin, "peer"),
+            UpgradeDecision::Upgrade
+        );
+        assert_eq!(
+            policy.decide(Some(&pin), &pin, "peer"),
+            UpgradeDecision::Ignore
+        );
+        assert_eq!(
+            policy.decide(Some(&version("0.8.0")), &version("0.10.0"), "peer"),
+            UpgradeDecision::Ignore
+        );
+    }
+
+    #[test]
+    fn ignores_sources_that_are_not_allowed() {
+        let policy = UpgradePolicy {
+            mode: UpgradeMode::Auto,
+            allowed_sources: vec!["trusted".into()],
+        };
+        let current = version("0.9.0");
+        let announced = version("0.10.0");
+        assert_eq!(
+            policy.decide(Some(&current), &announced, "trusted"),
+            UpgradeDecision::Upgrade
+        );
+        assert_eq!(
+            policy.decide(Some(&current), &announced, "other"),
+            UpgradeDecision::Ignore
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Default for the maximum size of a message, see [`NetworkConfig::max_message_size`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Default for the maximum size of a downloaded file, see [`NetworkConfig::max_file_size`].
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Configuration of the network layer, see [`crate::NetworkLayer::init`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// File with the identity private key. It is expected that the key is an
+    /// OpenSSL ed25519 private key in PEM format.
+    /// If no key is given, a new identity is generated.
+    pub private_key: Option<PathBuf>,
+    /// File in which the whitelist and known peer addresses are persisted,
+    /// so that they are restored on the next start.
+    pub peer_store: Option<PathBuf>,
+    /// Multiaddresses on which we listen for connections.
+    pub listen_addrs: Vec<String>,
+    /// Gossipsub topic on which messages are published. Only peers on the
+    /// same topic receive each others messages.
+    pub topic: String,
+    /// Messages larger than this many bytes are neither sent nor accepted.
+    /// All peers in a network should use the same maximum.
+    pub max_message_size: usize,
+    /// Downloads of files larger than this many bytes are aborted, see
+    /// [`crate::NetworkLayer::fetch_file`].
+    pub max_file_size: u64,
+    /// Number of peers that store each record in the DHT, see
+    /// [`crate::NetworkLayer::put_record`].
+    pub replication_factor: usize,
+    /// Seconds after which records expire, unless their publisher republishes
+    /// them.
+    pub record_ttl: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            private_key: None,
+            peer_store: None,
+            // Listen on all interfaces on an OS-assigned port.
+            listen_addrs: vec!["/ip4/0.0.0.0/tcp/0".into()],
+            topic: "topic".into(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            replication_factor: 2,
+            record_ttl: 7 * 24 * 60 * 60,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use futures::channel::{mpsc, oneshot};
+use std::{error, fmt, io};
+
+/// Errors of the network layer.
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The given string is not a valid peer id.
+    InvalidPeerId(String),
+    /// The private key file could not be read.
+    ReadPrivateKey(io::Error),
+    /// The private key file does not contain an OpenSSL ed25519 private key
+    /// in PEM format.
+    InvalidPrivateKey,
+    /// The given string is not a valid multiaddress to listen on.
+    InvalidListenAddr(String),
+    /// The message exceeds the maximum message size.
+    MessageTooLarge { size: usize, max: usize },
+    /// The record could not be stored in the local DHT store, e.g. because
+    /// it is too large.
+    RecordStore(String),
+    /// The task that runs the network stopped.
+    NetworkStopped,
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::InvalidPeerId(peer) => write!(f, "invalid peer id {:?}", peer),
+            NetworkError::ReadPrivateKey(e) => write!(f, "could not read private key: {}", e),
+            NetworkError::InvalidPrivateKey => {
+                write!(f, "private key is not an ed25519 key in PEM format")
+            }
+            NetworkError::InvalidListenAddr(addr) => {
+                write!(f, "invalid listen address {:?}", addr)
+            }
+            NetworkError::MessageTooLarge { size, max } => write!(
+                f,
+                "message of {} bytes exceeds the maximum of {} bytes",
+                size, max
+            ),
+            NetworkError::RecordStore(e) => write!(f, "could not store record: {}", e),
+            NetworkError::NetworkStopped => write!(f, "network task stopped"),
+        }
+    }
+}
+
+impl error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            NetworkError::ReadPrivateKey(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<mpsc::SendError> for NetworkError {
+    fn from(_: mpsc::SendError) -> Self {
+        NetworkError::NetworkStopped
+    }
+}
+
+impl From<oneshot::Canceled> for NetworkError {
+    fn from(_: oneshot::Canceled) -> Self {
+        NetworkError::NetworkStopped
+    }
+}
@@ -0,0 +1,253 @@
+use libp2p::{
+    core::{connection::ConnectionId, upgrade::DeniedUpgrade, ConnectedPoint},
+    swarm::{
+        ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, IntoConnectionHandler,
+        KeepAlive, NetworkBehaviour, NetworkBehaviourAction, PollParameters, SubstreamProtocol,
+    },
+    PeerId,
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    error, fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+use void::Void;
+
+/// Reason why a connection to a remote peer was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The remote peer is not on our whitelist.
+    NotWhitelisted,
+    /// The remote peer was blocked, e.g. because it sent invalid messages.
+    Blocked,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::NotWhitelisted => write!(f, "peer is not whitelisted"),
+            RejectReason::Blocked => write!(f, "peer is blocked"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GateEvent {
+    Rejected { peer: PeerId, reason: RejectReason },
+}
+
+// Network behaviour that gates connections based on our whitelist.
+//
+// The behaviour does not implement any protocol itself. Connections are
+// checked by the transport with a `GateCheck`, right after the handshake
+// authenticated the remote peer and before the connection is established, so
+// that connections to peers that are not allowed never reach the other
+// protocols. The behaviour reports these rejections.
+//
+// If the whitelist is empty, connections to all peers that are not blocked
+// are allowed.
+pub struct ConnectionGate {
+    rules: Arc<Mutex<Rules>>,
+}
+
+// State of a `ConnectionGate` that is shared with the transport.
+#[derive(Default)]
+struct Rules {
+    whitelisted: HashSet<PeerId>,
+    blocked: HashSet<PeerId>,
+    events: VecDeque<GateEvent>,
+    // Waker of the behaviour, to report rejections of the transport.
+    waker: Option<Waker>,
+}
+
+impl Rules {
+    // Why connections to the peer are rejected, `None` if they are allowed.
+    fn reject_reason(&self, peer: &PeerId) -> Option<RejectReason> {
+        if self.blocked.contains(peer) {
+            Some(RejectReason::Blocked)
+        } else if !self.whitelisted.is_empty() && !self.whitelisted.contains(peer) {
+            Some(RejectReason::NotWhitelisted)
+        } else {
+            None
+        }
+    }
+}
+
+impl ConnectionGate {
+    pub fn new() -> Self {
+        ConnectionGate {
+            rules: Arc::new(Mutex::new(Rules::default())),
+        }
+    }
+
+    // Check for the transport, which applies the rules of this gate.
+    pub fn check(&self) -> GateCheck {
+        GateCheck {
+            rules: self.rules.clone(),
+        }
+    }
+
+    pub fn whitelisted(&self) -> Vec<PeerId> {
+        self.rules().whitelisted.iter().cloned().collect()
+    }
+
+    pub fn is_whitelisted(&self, peer: &PeerId) -> bool {
+        self.rules().whitelisted.contains(peer)
+    }
+
+    // Whether connections to the peer are allowed.
+    pub fn is_allowed(&self, peer: &PeerId) -> bool {
+        self.rules().reject_reason(peer).is_none()
+    }
+
+    // Add a peer to the whitelist. Returns `false` if it was already whitelisted.
+    pub fn allow(&mut self, peer: PeerId) -> bool {
+        self.rules().whitelisted.insert(peer)
+    }
+
+    // Remove a peer from the whitelist. Returns `false` if it wasn't whitelisted.
+    pub fn deny(&mut self, peer: &PeerId) -> bool {
+        self.rules().whitelisted.remove(peer)
+    }
+
+    // Block all connections to a peer, even if it is whitelisted.
+    // Blocks are not persisted, they are lifted on restart.
+    pub fn block(&mut self, peer: PeerId) {
+        self.rules().blocked.insert(peer);
+    }
+
+    fn rules(&self) -> MutexGuard<'_, Rules> {
+        self.rules.lock().unwrap()
+    }
+}
+
+// Check of the remote peer of a new connection, see `ConnectionGate`.
+#[derive(Clone)]
+pub struct GateCheck {
+    rules: Arc<Mutex<Rules>>,
+}
+
+impl GateCheck {
+    // Whether a connection to the peer may be established. Rejections are
+    // reported by the `ConnectionGate`.
+    pub fn check(&self, peer: &PeerId) -> Result<(), ConnectionDenied> {
+        let mut rules = self.rules.lock().unwrap();
+        let reason = match rules.reject_reason(peer) {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+        rules.events.push_back(GateEvent::Rejected {
+            peer: *peer,
+            reason,
+        });
+        if let Some(waker) = rules.waker.take() {
+            waker.wake();
+        }
+        Err(ConnectionDenied(reason))
+    }
+}
+
+impl NetworkBehaviour for ConnectionGate {
+    type ConnectionHandler = GateHandlerProto;
+    type OutEvent = GateEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        GateHandlerProto
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: Void) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        let mut rules = self.rules();
+        if let Some(event) = rules.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        rules.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// The gate does not run any protocol on a connection, so its connection
+// handler does nothing.
+pub struct GateHandlerProto;
+
+impl IntoConnectionHandler for GateHandlerProto {
+    type Handler = GateHandler;
+
+    fn into_handler(self, _: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+        GateHandler
+    }
+
+    fn inbound_protocol(&self) -> DeniedUpgrade {
+        DeniedUpgrade
+    }
+}
+
+pub struct GateHandler;
+
+impl ConnectionHandler for GateHandler {
+    type InEvent = Void;
+    type OutEvent = Void;
+    type Error = Void;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Void;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(&mut self, protocol: Void, _: ()) {
+        void::unreachable(protocol)
+    }
+
+    fn inject_fully_negotiated_outbound(&mut self, protocol: Void, _: Void) {
+        void::unreachable(protocol)
+    }
+
+    fn inject_event(&mut self, event: Void) {
+        void::unreachable(event)
+    }
+
+    fn inject_dial_upgrade_error(&mut self, info: Void, _: ConnectionHandlerUpgrErr<Void>) {
+        void::unreachable(info)
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        Poll::Pending
+    }
+}
+
+// Error with which the transport fails a connection to a peer that is not allowed.
+#[derive(Debug)]
+pub struct ConnectionDenied(pub RejectReason);
+
+impl fmt::Display for ConnectionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection denied by gate: {}", self.0)
+    }
+}
+
+impl error::Error for ConnectionDenied {}
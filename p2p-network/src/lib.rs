@@ -1,145 +1,396 @@
+mod config;
+mod error;
+mod gate;
 mod network;
 mod protocol;
+mod store;
+mod transfer;
 
-use std::{path::Path, str::FromStr};
+use std::{
+    error, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
 
 use async_trait::async_trait;
 use futures::{
     channel::{mpsc, oneshot},
-    SinkExt,
+    future::BoxFuture,
+    FutureExt, SinkExt,
 };
 use libp2p::{
     identity::{self, ed25519},
-    PeerId,
+    Multiaddr, PeerId,
 };
-use network::{Command, Network};
+use network::{Command, Network, RecordConfig};
+use store::PeerStore;
+
+pub use config::{NetworkConfig, DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_MESSAGE_SIZE};
+pub use error::NetworkError;
+pub use gate::RejectReason;
 
 pub enum NetworkEvent {
     ConnectionClosed { peer: String },
     ConnectionEstablished { peer: String },
-    ConnectionRejected { peer: String },
+    ConnectionRejected { peer: String, reason: RejectReason },
     PeerDiscovered { peer: String },
     PeerExpired { peer: String },
     NewListenAddress { addr: String },
+    FileProgress { peer: String, key: String, received: u64, total: u64 },
+    FileReceived { peer: String, key: String },
+    FileTransferFailed { peer: String, key: String, reason: String },
+    DeliveryFailed { peer: String, error: DeliveryError },
+    /// Result of [`NetworkLayer::get_record`]: the values that the peers
+    /// storing the record returned, which may differ if the record was
+    /// overwritten concurrently. Empty if the record was not found.
+    RecordFetched { key: String, values: Vec<Vec<u8>> },
+    /// Another peer wants to store a record at us. It is only stored once it
+    /// is passed to [`NetworkLayer::store_record`].
+    RecordReceived { key: String, value: Vec<u8>, expires: Option<SystemTime> },
+}
+
+/// Reason why a direct message could not be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// The peer did not acknowledge the message in time.
+    TimedOut,
+    /// The connection to the peer was closed, or the peer does not support
+    /// our protocol.
+    Unreachable,
+    /// The peer could not be dialed.
+    DialFailure,
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::TimedOut => write!(f, "timed out"),
+            DeliveryError::Unreachable => write!(f, "peer is unreachable"),
+            DeliveryError::DialFailure => write!(f, "could not dial peer"),
+        }
+    }
 }
 
+impl error::Error for DeliveryError {}
+
+/// Future that resolves once a direct message was acknowledged by the
+/// receiving peer, or could not be delivered.
+pub type Delivery = BoxFuture<'static, Result<(), DeliveryError>>;
+
 pub struct NetworkComponent {
     command_tx: mpsc::Sender<Command>,
     local_peer_id: PeerId,
+    keypair: identity::Keypair,
+    max_message_size: usize,
+}
+
+impl NetworkComponent {
+    fn check_size(&self, message: &[u8]) -> Result<(), NetworkError> {
+        if message.len() > self.max_message_size {
+            return Err(NetworkError::MessageTooLarge {
+                size: message.len(),
+                max: self.max_message_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn parse_peer_id(peer: &str) -> Result<PeerId, NetworkError> {
+    PeerId::from_str(peer).map_err(|_| NetworkError::InvalidPeerId(peer.into()))
+}
+
+// Load an OpenSSL ed25519 private key in PEM format.
+fn load_keypair(path: &Path) -> Result<identity::Keypair, NetworkError> {
+    let sk_bytes = fs::read(path).map_err(NetworkError::ReadPrivateKey)?;
+    let static_secret = curve25519_parser::parse_openssl_25519_privkey(&sk_bytes)
+        .map_err(|_| NetworkError::InvalidPrivateKey)?;
+    let identity_ed25199_sk = ed25519::SecretKey::from_bytes(static_secret.to_bytes())
+        .map_err(|_| NetworkError::InvalidPrivateKey)?;
+    Ok(identity::Keypair::Ed25519(identity_ed25199_sk.into()))
+}
+
+/// Verify that `signature` is a valid signature of `data` by the owner of
+/// `public_key`, which is expected in protobuf encoding.
+/// Returns the id of the signing peer if the signature is valid.
+pub fn verify_signature(public_key: &[u8], data: &[u8], signature: &[u8]) -> Option<String> {
+    let public_key = identity::PublicKey::from_protobuf_encoding(public_key).ok()?;
+    public_key
+        .verify(data, signature)
+        .then(|| PeerId::from_public_key(&public_key).to_base58())
 }
 
 #[async_trait]
 pub trait NetworkLayer {
-    /// Create a new network.
+    /// Create a new network, see [`NetworkConfig`] for its options.
     /// Inbound messages from remote peers are forwarded as (sender, message) 
     /// tuple through `in_message_tx`.
-    /// Optionally the identity private key may be loaded from a file. It is 
-    /// expected that the key is an OpenSSL ed25519 private key in PEM format.
     fn init(
-        private_key: Option<&Path>,
+        config: NetworkConfig,
         in_message_tx: mpsc::Sender<(String, Vec<u8>, bool)>,
         event_tx: mpsc::Sender<NetworkEvent>,
-    ) -> Self;
+    ) -> Result<Self, NetworkError>
+    where
+        Self: Sized;
     /// Our own unique id in the network.
     fn local_peer_id(&self) -> String;
+    /// Our own public key in protobuf encoding.
+    fn public_key(&self) -> Vec<u8>;
+    /// Sign data with our identity keypair.
+    /// The signature can be verified with [`verify_signature`].
+    fn sign(&self, data: &[u8]) -> Vec<u8>;
     /// Publish a message to the whole network.
-    async fn publish_message(&mut self, message: Vec<u8>);
+    async fn publish_message(&mut self, message: Vec<u8>) -> Result<(), NetworkError>;
     /// Send a direct message to one peer.
-    async fn send_message(&mut self, peer: String, message: Vec<u8>);
+    /// The returned future resolves to the delivery result. Failed deliveries
+    /// are also reported through `NetworkEvent::DeliveryFailed`.
+    async fn send_message(&mut self, peer: String, message: Vec<u8>)
+        -> Result<Delivery, NetworkError>;
     /// Get the list of currently whitelisted peers.
     /// This is the list of peers for which we allow sending and receiving 
     /// messages on the network layer.
-    async fn get_whitelisted(&mut self) -> Vec<String>;
+    async fn get_whitelisted(&mut self) -> Result<Vec<String>, NetworkError>;
     /// Add a peer to our local whitelist.
-    async fn add_whitelisted(&mut self, peer: String);
-    /// Remove a peer from our local whitelist.
-    async fn remove_whitelisted(&mut self, peer: String);
+    async fn add_whitelisted(&mut self, peer: String) -> Result<(), NetworkError>;
+    /// Remove a peer from our local whitelist and close all connections to it.
+    async fn remove_whitelisted(&mut self, peer: String) -> Result<(), NetworkError>;
+    /// Close all connections to a peer and reject new ones, even if the peer
+    /// is whitelisted. The block lasts until the network is restarted.
+    async fn block_peer(&mut self, peer: String) -> Result<(), NetworkError>;
+    /// Provide the file at `path` to remote peers that request it by `key`.
+    async fn provide_file(&mut self, key: String, path: PathBuf) -> Result<(), NetworkError>;
+    /// Stop providing the file with the given key.
+    async fn stop_providing(&mut self, key: String) -> Result<(), NetworkError>;
+    /// Store a record in the Kademlia DHT, at the peers whose ids are closest
+    /// to `key`. We republish the record while we are running; otherwise it
+    /// expires after the configured record TTL. Records with an explicit
+    /// `expires` time are dropped at that time in any case.
+    async fn put_record(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<SystemTime>,
+    ) -> Result<(), NetworkError>;
+    /// Look up the record with the given key in the DHT.
+    /// The result is reported through `NetworkEvent::RecordFetched`.
+    async fn get_record(&mut self, key: String) -> Result<(), NetworkError>;
+    /// Store a record in our part of the DHT, without publishing it, e.g. a
+    /// record of `NetworkEvent::RecordReceived` that the application accepted.
+    async fn store_record(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<SystemTime>,
+    ) -> Result<(), NetworkError>;
+    /// Download the file that `peer` provides under `key` to `path`.
+    /// The progress of the download is reported through `NetworkEvent::FileProgress`,
+    /// its completion through `NetworkEvent::FileReceived` or
+    /// `NetworkEvent::FileTransferFailed`. Files that are larger than
+    /// [`NetworkConfig::max_file_size`] are not downloaded.
+    async fn fetch_file(
+        &mut self,
+        peer: String,
+        key: String,
+        path: PathBuf,
+    ) -> Result<(), NetworkError>;
 }
 
 #[async_trait]
 impl NetworkLayer for NetworkComponent {
     fn init(
-        private_key: Option<&Path>,
+        config: NetworkConfig,
         in_message_tx: mpsc::Sender<(String, Vec<u8>, bool)>,
         event_tx: mpsc::Sender<NetworkEvent>,
-    ) -> Self {
+    ) -> Result<Self, NetworkError> {
         let (command_tx, command_rx) = mpsc::channel(0);
+        let listen_addrs = config
+            .listen_addrs
+            .iter()
+            .map(|addr| {
+                addr.parse::<Multiaddr>()
+                    .map_err(|_| NetworkError::InvalidListenAddr(addr.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let max_message_size = config.max_message_size;
+        let max_file_size = config.max_file_size;
+        let record_config = RecordConfig {
+            replication_factor: config.replication_factor,
+            record_ttl: Duration::from_secs(config.record_ttl),
+        };
 
         // Load an ed25519 keypair from file or generate a new one.
         //
         // Used to derive a unique PeerId and the keypair for encryption on the
         // Transport layer with the Noise protocol (https://noiseprotocol.org/noise.html).
         //
-        let keypair = private_key
-            .and_then(|path| {
-                let sk_bytes = std::fs::read(path).ok()?;
-                let static_secret =
-                    curve25519_parser::parse_openssl_25519_privkey(&sk_bytes).ok()?;
-                let identity_ed25199_sk =
-                    ed25519::SecretKey::from_bytes(static_secret.to_bytes()).ok()?;
-                Some(identity::Keypair::Ed25519(identity_ed25199_sk.into()))
-            })
-            .unwrap_or_else(identity::Keypair::generate_ed25519);
+        let keypair = match config.private_key.as_deref() {
+            Some(path) => load_keypair(path)?,
+            None => identity::Keypair::generate_ed25519(),
+        };
         let local_peer_id = PeerId::from_public_key(&keypair.public());
+        let peer_store = config.peer_store.as_deref().map(PeerStore::new);
+        let network_keypair = keypair.clone();
 
         async_std::task::spawn(async {
             // All logic is implement in our `network` mod.
             // Refer to its docs for more info on the below method calls.
-            let mut network = Network::new(keypair, command_rx, in_message_tx, event_tx).await;
-            network.start_listening();
+            let mut network = Network::new(
+                network_keypair,
+                peer_store,
+                max_message_size,
+                max_file_size,
+                record_config,
+                config.topic,
+                command_rx,
+                in_message_tx,
+                event_tx,
+            )
+            .await;
+            network.start_listening(listen_addrs);
             network.subscribe();
+            network.dial_whitelisted().await;
             network.run().await
         });
-        NetworkComponent {
+        Ok(NetworkComponent {
             command_tx,
             local_peer_id,
-        }
+            keypair,
+            max_message_size,
+        })
     }
 
     fn local_peer_id(&self) -> String {
         self.local_peer_id.to_base58()
     }
 
-    async fn publish_message(&mut self, message: Vec<u8>) {
+    fn public_key(&self) -> Vec<u8> {
+        self.keypair.public().to_protobuf_encoding()
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.keypair
+            .sign(data)
+            .expect("Signing with an ed25519 keypair never fails.")
+    }
+
+    async fn publish_message(&mut self, message: Vec<u8>) -> Result<(), NetworkError> {
+        self.check_size(&message)?;
         let command = Command::PublishMessage { message };
-        self.command_tx.send(command).await.unwrap();
+        self.command_tx.send(command).await?;
+        Ok(())
     }
 
-    async fn send_message(&mut self, peer: String, message: Vec<u8>) {
-        let peer = match PeerId::from_str(&peer) {
-            Ok(p) => p,
-            Err(_) => return,
-        };
-        let command = Command::SendMessage { peer, message };
-        self.command_tx.send(command).await.unwrap();
+    async fn send_message(
+        &mut self,
+        peer: String,
+        message: Vec<u8>,
+    ) -> Result<Delivery, NetworkError> {
+        self.check_size(&message)?;
+        let peer = parse_peer_id(&peer)?;
+        let (tx, rx) = oneshot::channel();
+        let command = Command::SendMessage { peer, message, tx };
+        self.command_tx.send(command).await?;
+        // The sender is only dropped if the network task stopped.
+        Ok(rx
+            .map(|result| result.unwrap_or(Err(DeliveryError::Unreachable)))
+            .boxed())
     }
 
-    async fn get_whitelisted(&mut self) -> Vec<String> {
+    async fn get_whitelisted(&mut self) -> Result<Vec<String>, NetworkError> {
         let (tx, rx) = oneshot::channel();
         let command = Command::GetWhitelisted { tx };
-        self.command_tx.send(command).await.unwrap();
-        rx.await
-            .unwrap()
-            .into_iter()
-            .map(|id| id.to_base58())
-            .collect()
-    }
-
-    async fn add_whitelisted(&mut self, peer: String) {
-        let peer = match PeerId::from_str(&peer) {
-            Ok(p) => p,
-            Err(_) => return,
-        };
+        self.command_tx.send(command).await?;
+        Ok(rx.await?.into_iter().map(|id| id.to_base58()).collect())
+    }
+
+    async fn add_whitelisted(&mut self, peer: String) -> Result<(), NetworkError> {
+        let peer = parse_peer_id(&peer)?;
         let command = Command::AddWhitelisted { peer };
-        self.command_tx.send(command).await.unwrap();
+        self.command_tx.send(command).await?;
+        Ok(())
     }
 
-    async fn remove_whitelisted(&mut self, peer: String) {
-        let peer = match PeerId::from_str(&peer) {
-            Ok(p) => p,
-            Err(_) => return,
-        };
+    async fn remove_whitelisted(&mut self, peer: String) -> Result<(), NetworkError> {
+        let peer = parse_peer_id(&peer)?;
         let command = Command::RemoveWhitelisted { peer };
-        self.command_tx.send(command).await.unwrap();
+        self.command_tx.send(command).await?;
+        Ok(())
+    }
+
+    async fn block_peer(&mut self, peer: String) -> Result<(), NetworkError> {
+        let peer = parse_peer_id(&peer)?;
+        let command = Command::BlockPeer { peer };
+        self.command_tx.send(command).await?;
+        Ok(())
+    }
+
+    async fn provide_file(&mut self, key: String, path: PathBuf) -> Result<(), NetworkError> {
+        let command = Command::ProvideFile { key, path };
+        self.command_tx.send(command).await?;
+        Ok(())
+    }
+
+    async fn stop_providing(&mut self, key: String) -> Result<(), NetworkError> {
+        let command = Command::StopProviding { key };
+        self.command_tx.send(command).await?;
+        Ok(())
+    }
+
+    async fn put_record(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<SystemTime>,
+    ) -> Result<(), NetworkError> {
+        self.check_size(&value)?;
+        let expires = expires.map(|t| {
+            Instant::now() + t.duration_since(SystemTime::now()).unwrap_or_default()
+        });
+        let (tx, rx) = oneshot::channel();
+        let command = Command::PutRecord {
+            key,
+            value,
+            expires,
+            tx,
+        };
+        self.command_tx.send(command).await?;
+        rx.await?
+    }
+
+    async fn get_record(&mut self, key: String) -> Result<(), NetworkError> {
+        let command = Command::GetRecord { key };
+        self.command_tx.send(command).await?;
+        Ok(())
+    }
+
+    async fn store_record(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<SystemTime>,
+    ) -> Result<(), NetworkError> {
+        let expires = expires.map(|t| {
+            Instant::now() + t.duration_since(SystemTime::now()).unwrap_or_default()
+        });
+        let command = Command::StoreRecord {
+            key,
+            value,
+            expires,
+        };
+        self.command_tx.send(command).await?;
+        Ok(())
+    }
+
+    async fn fetch_file(
+        &mut self,
+        peer: String,
+        key: String,
+        path: PathBuf,
+    ) -> Result<(), NetworkError> {
+        let peer = parse_peer_id(&peer)?;
+        let command = Command::FetchFile { peer, key, path };
+        self.command_tx.send(command).await?;
+        Ok(())
     }
 }
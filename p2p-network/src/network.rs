@@ -1,32 +1,94 @@
 use futures::{
     channel::{mpsc, oneshot},
-    select, SinkExt, StreamExt,
+    future, select, SinkExt, StreamExt,
 };
 use libp2p::{
-    core,
+    core::{self, muxing::StreamMuxerBox},
     gossipsub::{
-        error::PublishError, Gossipsub, GossipsubConfig, GossipsubEvent, GossipsubMessage,
+        error::PublishError, Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage,
         IdentTopic, MessageAuthenticity,
     },
     identity,
+    kad::{
+        record::{
+            store::{MemoryStore, MemoryStoreConfig, RecordStore},
+            Key,
+        },
+        GetRecordError, GetRecordOk, InboundRequest, Kademlia, KademliaConfig, KademliaEvent,
+        KademliaStoreInserts, QueryId, QueryResult, Quorum, Record, K_VALUE,
+    },
     mdns::{Mdns, MdnsConfig, MdnsEvent},
     mplex, noise,
     swarm::{dial_opts::DialOpts, SwarmEvent, DialError},
-    tcp, yamux, Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport, request_response::{RequestResponse, RequestResponseConfig, RequestResponseEvent, ProtocolSupport, RequestResponseMessage},
+    tcp, yamux, Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport, request_response::{RequestResponse, RequestResponseConfig, RequestResponseEvent, ProtocolSupport, RequestResponseMessage, RequestId, OutboundFailure},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    iter,
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
 };
-use std::{collections::{HashMap, HashSet}, iter};
 
-use crate::{NetworkEvent, protocol::{Codec, Protocol, Ack}};
+use crate::{
+    gate::{ConnectionGate, GateEvent},
+    protocol::{Ack, Codec, Protocol},
+    store::PeerStore,
+    transfer::{ChunkRequest, ChunkResponse, FileCodec, FileProtocol},
+    DeliveryError, NetworkError, NetworkEvent,
+};
 
-// Fixed topic for the first PoC.
-const TOPIC: &str = "topic";
+// Space for the gossipsub framing, i.e. source, sequence number, topic and
+// signature, on top of the message itself.
+const GOSSIPSUB_OVERHEAD: usize = 1024;
+// Space for the framing of a Kademlia request, i.e. the key and the
+// publisher of a record, on top of its value.
+const KADEMLIA_OVERHEAD: usize = 1024;
+
+// Options of the Kademlia DHT.
+pub struct RecordConfig {
+    // Number of peers that store each record.
+    pub replication_factor: usize,
+    // Time after which records expire unless they are republished.
+    pub record_ttl: Duration,
+}
 
 pub enum Command {
     PublishMessage { message: Vec<u8> },
-    SendMessage { peer: PeerId, message: Vec<u8> },
+    SendMessage {
+        peer: PeerId,
+        message: Vec<u8>,
+        tx: oneshot::Sender<Result<(), DeliveryError>>,
+    },
     GetWhitelisted { tx: oneshot::Sender<Vec<PeerId>> },
     AddWhitelisted { peer: PeerId },
     RemoveWhitelisted { peer: PeerId },
+    BlockPeer { peer: PeerId },
+    ProvideFile { key: String, path: PathBuf },
+    StopProviding { key: String },
+    FetchFile { peer: PeerId, key: String, path: PathBuf },
+    PutRecord {
+        key: String,
+        value: Vec<u8>,
+        expires: Option<Instant>,
+        tx: oneshot::Sender<Result<(), NetworkError>>,
+    },
+    GetRecord { key: String },
+    StoreRecord {
+        key: String,
+        value: Vec<u8>,
+        expires: Option<Instant>,
+    },
+}
+
+// State of an ongoing file download.
+struct Download {
+    peer: PeerId,
+    key: String,
+    file: File,
+    received: u64,
 }
 
 // Central structure of this application, that holds the swarm.
@@ -41,15 +103,40 @@ pub struct Network {
     inbound_message_tx: mpsc::Sender<(String, Vec<u8>, bool)>,
     event_tx: mpsc::Sender<NetworkEvent>,
 
-    whitelisted: Vec<PeerId>,
-
+    // Known addresses of remote peers, including the ones whose connections
+    // were rejected, so that they can be dialed once they are whitelisted.
     addresses: HashMap<PeerId, Vec<Multiaddr>>,
+
+    // Optional on-disk store for the whitelist and the known addresses.
+    peer_store: Option<PeerStore>,
+
+    // Senders for the delivery results of direct messages that were not
+    // acknowledged yet.
+    pending_deliveries: HashMap<RequestId, oneshot::Sender<Result<(), DeliveryError>>>,
+
+    // Files that we provide to other peers, by the key they are requested with.
+    provided: HashMap<String, PathBuf>,
+    // Ongoing downloads, by the id of the request for their next chunk.
+    downloads: HashMap<RequestId, Download>,
+    // Downloads of larger files are aborted, before they fill up the disk.
+    max_file_size: u64,
+
+    // Keys of ongoing record lookups, by the id of their query.
+    record_queries: HashMap<QueryId, String>,
+
+    // Peers with connections that were reported as established.
+    connected: HashSet<PeerId>,
 }
 
 impl Network {
     // Create a new instance of `Network.`
     pub async fn new(
         keypair: identity::Keypair,
+        peer_store: Option<PeerStore>,
+        max_message_size: usize,
+        max_file_size: u64,
+        record_config: RecordConfig,
+        topic: String,
         command_rx: mpsc::Receiver<Command>,
         inbound_message_tx: mpsc::Sender<(String, Vec<u8>, bool)>,
         event_tx: mpsc::Sender<NetworkEvent>,
@@ -57,11 +144,21 @@ impl Network {
         let local_peer_id = PeerId::from_public_key(&keypair.public());
         println!("[Network] Local PeerId: {}", local_peer_id);
 
-        // Create a transport. The transport controls **how** we sent out data to the remote peer.
-        let tcp_transport = tcp::TcpConfig::new();
         let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
             .into_authentic(&keypair)
             .expect("Signing libp2p-noise static DH keypair failed.");
+
+        // Create a behaviour. The behaviour controls **what** we sent to the remote.
+        // We use a custom behehaviour (see `Behaviour` docs).
+        let mut behaviour = Behaviour::new(keypair, max_message_size, record_config)
+            .await
+            .unwrap();
+
+        // Create a transport. The transport controls **how** we sent out data to the remote peer.
+        // Once the handshake authenticated the remote peer, our `ConnectionGate` decides
+        // whether the connection is established at all.
+        let gate = behaviour.gate.check();
+        let tcp_transport = tcp::TcpConfig::new();
         let transport = tcp_transport
             .upgrade(core::upgrade::Version::V1)
             .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
@@ -70,18 +167,30 @@ impl Network {
                 mplex::MplexConfig::default(),
             ))
             .timeout(std::time::Duration::from_secs(20))
+            .and_then(move |(peer, muxer), _| {
+                future::ready(gate.check(&peer).map(|()| (peer, StreamMuxerBox::new(muxer))))
+            })
             .boxed();
 
-        // Create a behaviour. The behaviour controls **what** we sent to the remote.
-        // We use a custom behehaviour (see `Behaviour` docs).
-        let behaviour = Behaviour::new(keypair).await.unwrap();
+        // Restore whitelist and addresses from a previous run.
+        let mut addresses = HashMap::new();
+        if let Some(store) = peer_store.as_ref() {
+            match store.load() {
+                Ok((whitelisted, addrs)) => {
+                    for peer in whitelisted {
+                        behaviour.gate.allow(peer);
+                    }
+                    addresses = addrs;
+                }
+                Err(e) => println!("[Network] Could not load peer store: {:?}", e),
+            }
+        }
 
         // The swarm is libp2p single entry point that controls all network interaction.
         // It wraps the transport and the behaviour.
         let swarm = Swarm::new(transport, behaviour, local_peer_id);
 
-        // Dummy topic for testing
-        let topic = IdentTopic::new(TOPIC);
+        let topic = IdentTopic::new(topic);
 
         // Return `Self`.
         Network {
@@ -90,22 +199,39 @@ impl Network {
             inbound_message_tx,
             command_rx,
             event_tx,
-            whitelisted: Vec::new(),
-            addresses: HashMap::new(),
+            addresses,
+            peer_store,
+            pending_deliveries: HashMap::new(),
+            provided: HashMap::new(),
+            downloads: HashMap::new(),
+            max_file_size,
+            record_queries: HashMap::new(),
+            connected: HashSet::new(),
         }
     }
 
-    // Start listening on the network on all interfaces (localhost, local network, etc.)
-    pub fn start_listening(&mut self) {
-        // Create an unspecified address (all zeroes).
-        // This causes us to listen on all network interfaces on an OS-assigned address.
-        let address: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse().unwrap();
+    // Start listening on the network on the given addresses, e.g. an
+    // unspecified address (all zeroes) to listen on all interfaces.
+    pub fn start_listening(&mut self, addresses: Vec<Multiaddr>) {
+        for address in addresses {
+            // Tell the swarm to start listening.
+            if let Err(e) = self.swarm.listen_on(address.clone()) {
+                println!("[Network] Could not listen on {}: {:?}", address, e);
+            }
+        }
+    }
 
-        // Tell the swarm to start listening.
-        self.swarm.listen_on(address).unwrap();
+    // Dial all whitelisted peers for which we know an address, e.g. from the
+    // peer store, without waiting for them to be discovered via mDNS.
+    pub async fn dial_whitelisted(&mut self) {
+        for peer in self.swarm.behaviour().gate.whitelisted() {
+            if self.addresses.contains_key(&peer) {
+                self.dial_to_peer(peer).await;
+            }
+        }
     }
 
-    // Subscribe to our dummy topic.
+    // Subscribe to our topic.
     pub fn subscribe(&mut self) {
         self.swarm
             .behaviour_mut()
@@ -138,11 +264,14 @@ impl Network {
     async fn handle_command(&mut self, command: Command) {
         match command {
             Command::PublishMessage { message } => self.publish_msg_to_swarm(&message),
-            Command::SendMessage { peer, message }  => self.send_message(&peer, message),
-            Command::GetWhitelisted { tx } => tx.send(self.whitelisted.clone()).unwrap(),
+            Command::SendMessage { peer, message, tx } => self.send_message(&peer, message, tx),
+            Command::GetWhitelisted { tx } => {
+                let whitelisted = self.swarm.behaviour().gate.whitelisted();
+                tx.send(whitelisted).unwrap()
+            }
             Command::AddWhitelisted { peer } => {
-                if !self.whitelisted.contains(&peer) {
-                    self.whitelisted.push(peer);
+                if self.swarm.behaviour_mut().gate.allow(peer) {
+                    self.persist();
                 }
 
                 // Maybe this is not so smart, when updating larger networks, since everyone would start
@@ -150,11 +279,107 @@ impl Network {
                 self.dial_to_peer(peer).await;
             }
             Command::RemoveWhitelisted { peer } => {
-                self.whitelisted.retain(|p| p != &peer);
+                if self.swarm.behaviour_mut().gate.deny(&peer) {
+                    self.persist();
+                }
+                self.swarm.behaviour_mut().kademlia.remove_peer(&peer);
+                if self.swarm.is_connected(&peer) {
+                    println!("[Network] Disconnecting from removed peer {:?}", peer);
+                    let _ = self.swarm.disconnect_peer_id(peer);
+                }
+            }
+            Command::BlockPeer { peer } => {
+                self.swarm.behaviour_mut().gate.block(peer);
+                self.swarm.behaviour_mut().kademlia.remove_peer(&peer);
+                if self.swarm.is_connected(&peer) {
+                    println!("[Network] Disconnecting from blocked peer {:?}", peer);
+                    let _ = self.swarm.disconnect_peer_id(peer);
+                }
+            }
+            Command::ProvideFile { key, path } => {
+                self.provided.insert(key, path);
+            }
+            Command::StopProviding { key } => {
+                self.provided.remove(&key);
+            }
+            Command::FetchFile { peer, key, path } => self.fetch_file(peer, key, path).await,
+            Command::PutRecord {
+                key,
+                value,
+                expires,
+                tx,
+            } => {
+                let mut record = Record::new(Key::from(key.into_bytes()), value);
+                // Without an expiry, the record TTL of the config applies.
+                record.expires = expires;
+                let result = self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .put_record(record, Quorum::One)
+                    .map(|_| ())
+                    .map_err(|e| NetworkError::RecordStore(format!("{:?}", e)));
+                let _ = tx.send(result);
+            }
+            Command::StoreRecord { key, value, expires } => {
+                let mut record = Record::new(Key::from(key.into_bytes()), value);
+                record.expires = expires;
+                if let Err(e) = self.swarm.behaviour_mut().kademlia.store_mut().put(record) {
+                    println!("[Network] Could not store record: {:?}", e);
+                }
+            }
+            Command::GetRecord { key } => {
+                // Ask all peers that should store the record, since some of
+                // them may have an outdated value.
+                let query = self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_record(Key::from(key.clone().into_bytes()), Quorum::All);
+                self.record_queries.insert(query, key);
             }
         }
     }
 
+    // Start downloading the file that `peer` provides under `key`.
+    async fn fetch_file(&mut self, peer: PeerId, key: String, path: PathBuf) {
+        let file = match File::create(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                self.event_tx
+                    .send(NetworkEvent::FileTransferFailed {
+                        peer: peer.to_base58(),
+                        key,
+                        reason: format!("could not create {:?}: {}", path, e),
+                    })
+                    .await
+                    .unwrap();
+                return;
+            }
+        };
+        println!("[Network] Fetching {:?} from {:?}", key, peer);
+        let download = Download {
+            peer,
+            key,
+            file,
+            received: 0,
+        };
+        self.request_next_chunk(download);
+    }
+
+    fn request_next_chunk(&mut self, download: Download) {
+        let request = ChunkRequest {
+            key: download.key.clone(),
+            offset: download.received,
+        };
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .file_transfer
+            .send_request(&download.peer, request);
+        self.downloads.insert(request_id, download);
+    }
+
     // Publish the message in the gossipsub network
     fn publish_msg_to_swarm(&mut self, input: &[u8]) {
         match self
@@ -172,8 +397,16 @@ impl Network {
     }
     
     // Send a message to a single peer.
-    fn send_message(&mut self, peer: &PeerId, data: Vec<u8>) {
-        let _ = self.swarm.behaviour_mut().request_response.send_request(peer, data);
+    // The result is sent through `tx` once the peer acknowledged the message,
+    // or the request failed.
+    fn send_message(
+        &mut self,
+        peer: &PeerId,
+        data: Vec<u8>,
+        tx: oneshot::Sender<Result<(), DeliveryError>>,
+    ) {
+        let request_id = self.swarm.behaviour_mut().request_response.send_request(peer, data);
+        self.pending_deliveries.insert(request_id, tx);
     }
 
     // Handle an event on our swarm.
@@ -181,32 +414,38 @@ impl Network {
     // (e.g. new/ expired listening address) or events from our `Behaviour`.
     async fn handle_swarm_event<E>(&mut self, event: SwarmEvent<Event, E>) {
         match event {
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                if self.whitelisted.is_empty() || self.whitelisted.contains(&peer_id) {
-                    println!("[Network] Connected to {:?}", peer_id);
-
-                    self.event_tx
-                        .send(NetworkEvent::ConnectionEstablished {
-                            peer: peer_id.to_base58(),
-                        })
-                        .await
-                        .unwrap();
-                } else {
-                    // TODO: reject connection without loosing the association from peer id to address
-                    // (after disconnect_peer_id, connect(peer_id) fails with no_address)
-                    println!(
-                        "[Network] Disconnecting connection from not whitelisted peer {:?}",
-                        peer_id
-                    );
-                    let _ = self.swarm.disconnect_peer_id(peer_id);
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                // Remember the address that we dialed, so that we can reconnect to the
+                // peer later.
+                if endpoint.is_dialer() {
+                    self.add_address(peer_id, endpoint.get_remote_address().clone());
+                }
 
-                    self.event_tx
-                        .send(NetworkEvent::ConnectionRejected {
-                            peer: peer_id.to_base58(),
-                        })
-                        .await
-                        .unwrap();
+                // Connections to peers that are not allowed are denied by our
+                // `ConnectionGate` during the handshake, which reports them separately.
+                // A peer may have been blocked since then though.
+                if !self.swarm.behaviour().gate.is_allowed(&peer_id) {
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    return;
+                }
+                println!("[Network] Connected to {:?}", peer_id);
+
+                // Only whitelisted peers are part of the DHT.
+                let addresses = self.addresses.get(&peer_id).cloned().unwrap_or_default();
+                for address in addresses {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, address);
                 }
+
+                self.connected.insert(peer_id);
+                self.event_tx
+                    .send(NetworkEvent::ConnectionEstablished {
+                        peer: peer_id.to_base58(),
+                    })
+                    .await
+                    .unwrap();
             }
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("[Network] Listening on {:?}", address);
@@ -229,7 +468,34 @@ impl Network {
             SwarmEvent::Behaviour(Event::ReqRes(ev)) => {
                 self.handle_req_res_event(ev).await;
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
+            // Event issued by the file transfer protocol behaviour.
+            SwarmEvent::Behaviour(Event::FileTransfer(ev)) => {
+                self.handle_file_transfer_event(ev).await;
+            }
+            // Event issued by the Kademlia DHT behaviour.
+            SwarmEvent::Behaviour(Event::Kademlia(ev)) => {
+                self.handle_kademlia_event(ev).await;
+            }
+            // Event issued by our connection gate.
+            SwarmEvent::Behaviour(Event::Gate(GateEvent::Rejected { peer, reason })) => {
+                println!("[Network] Rejected connection from {:?}: {}", peer, reason);
+                self.event_tx
+                    .send(NetworkEvent::ConnectionRejected {
+                        peer: peer.to_base58(),
+                        reason,
+                    })
+                    .await
+                    .unwrap();
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established, .. } => {
+                // Also report the connections of peers that were revoked or blocked
+                // while they were connected.
+                if !self.connected.contains(&peer_id) {
+                    return;
+                }
+                if num_established == 0 {
+                    self.connected.remove(&peer_id);
+                }
                 println!("[Network] Connection to {:?} closed.", peer_id);
                 self.event_tx
                     .send(NetworkEvent::ConnectionClosed {
@@ -252,8 +518,7 @@ impl Network {
             let mut distinct_peers = HashSet::new();
             for (peer, addr) in discovered {
                 distinct_peers.insert(peer);
-                let addrs = self.addresses.entry(peer).or_default();
-                addrs.push(addr);
+                self.add_address(peer, addr);
             }
             for peer in distinct_peers {
                 self.event_tx
@@ -263,7 +528,7 @@ impl Network {
                     .await
                     .unwrap();
 
-                if self.whitelisted.contains(&peer) {
+                if self.swarm.behaviour().gate.is_whitelisted(&peer) {
                     println!("[Network] Connecting to whitelisted peer {:?}", peer);
                     self.dial_to_peer(peer).await;
                 } else {
@@ -282,6 +547,24 @@ impl Network {
         }
     }
 
+    fn add_address(&mut self, peer: PeerId, address: Multiaddr) {
+        let addrs = self.addresses.entry(peer).or_default();
+        if !addrs.contains(&address) {
+            addrs.push(address);
+            self.persist();
+        }
+    }
+
+    // Write the current whitelist and addresses to the peer store, if any.
+    fn persist(&self) {
+        if let Some(store) = self.peer_store.as_ref() {
+            let whitelisted = self.swarm.behaviour().gate.whitelisted();
+            if let Err(e) = store.save(&whitelisted, &self.addresses) {
+                println!("[Network] Could not write peer store: {:?}", e);
+            }
+        }
+    }
+
     async fn dial_to_peer(&mut self, peer: PeerId) {
         if !self.addresses.contains_key(&peer) {
             println!("[Network] Could not find addresses for {:?}", peer);
@@ -321,12 +604,213 @@ impl Network {
 
     // Handle event created by our inner Request Response behaviour.
     async fn handle_req_res_event(&mut self, event: RequestResponseEvent<Vec<u8>, Ack>) {
-        if let RequestResponseEvent::Message { peer, message:
-        RequestResponseMessage::Request { request_id: _, request, channel } } = event {
-            let _ = self.swarm.behaviour_mut().request_response.send_response(channel, Ack);
-            self.inbound_message_tx.send((peer.to_base58(), request, false)).await.unwrap();
+        match event {
+            RequestResponseEvent::Message { peer, message:
+            RequestResponseMessage::Request { request_id: _, request, channel } } => {
+                let _ = self.swarm.behaviour_mut().request_response.send_response(channel, Ack);
+                self.inbound_message_tx.send((peer.to_base58(), request, false)).await.unwrap();
+            }
+            RequestResponseEvent::Message { message:
+            RequestResponseMessage::Response { request_id, .. }, .. } => {
+                if let Some(tx) = self.pending_deliveries.remove(&request_id) {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                let error = match error {
+                    OutboundFailure::DialFailure => DeliveryError::DialFailure,
+                    OutboundFailure::Timeout => DeliveryError::TimedOut,
+                    OutboundFailure::ConnectionClosed
+                    | OutboundFailure::UnsupportedProtocols => DeliveryError::Unreachable,
+                };
+                println!("[Network] Could not deliver message to {:?}: {}", peer, error);
+                if let Some(tx) = self.pending_deliveries.remove(&request_id) {
+                    let _ = tx.send(Err(error));
+                }
+                self.event_tx
+                    .send(NetworkEvent::DeliveryFailed {
+                        peer: peer.to_base58(),
+                        error,
+                    })
+                    .await
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    // Handle event created by our inner Kademlia behaviour.
+    async fn handle_kademlia_event(&mut self, event: KademliaEvent) {
+        let (id, result) = match event {
+            KademliaEvent::OutboundQueryCompleted { id, result, .. } => (id, result),
+            // Records that other peers put are only stored once the
+            // application accepted them, see `Command::StoreRecord`.
+            KademliaEvent::InboundRequest {
+                request: InboundRequest::PutRecord { record: Some(record), .. },
+            } => {
+                let key = match String::from_utf8(record.key.to_vec()) {
+                    Ok(key) => key,
+                    Err(_) => return,
+                };
+                let expires = record.expires.map(|t| {
+                    SystemTime::now() + t.saturating_duration_since(Instant::now())
+                });
+                self.event_tx
+                    .send(NetworkEvent::RecordReceived {
+                        key,
+                        value: record.value,
+                        expires,
+                    })
+                    .await
+                    .unwrap();
+                return;
+            }
+            _ => return,
+        };
+        match result {
+            QueryResult::GetRecord(result) => {
+                let key = match self.record_queries.remove(&id) {
+                    Some(key) => key,
+                    None => return,
+                };
+                let records = match result {
+                    Ok(GetRecordOk { records, .. }) => records,
+                    // Fewer peers than the replication factor returned the
+                    // record, e.g. because the network is small.
+                    Err(GetRecordError::QuorumFailed { records, .. })
+                    | Err(GetRecordError::Timeout { records, .. }) => records,
+                    Err(GetRecordError::NotFound { .. }) => Vec::new(),
+                };
+                self.event_tx
+                    .send(NetworkEvent::RecordFetched {
+                        key,
+                        values: records.into_iter().map(|r| r.record.value).collect(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            QueryResult::PutRecord(Err(e)) | QueryResult::RepublishRecord(Err(e)) => {
+                println!("[Network] Could not store record: {:?}", e);
+            }
+            _ => {}
         }
     }
+
+    // Handle event created by our inner file transfer behaviour.
+    async fn handle_file_transfer_event(
+        &mut self,
+        event: RequestResponseEvent<ChunkRequest, ChunkResponse>,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { request, channel, .. },
+            } => {
+                let response = match self.provided.get(&request.key) {
+                    Some(path) => ChunkResponse::read(path, request.offset).unwrap_or_else(|e| {
+                        println!("[Network] Could not read {:?}: {:?}", path, e);
+                        ChunkResponse::NotFound
+                    }),
+                    None => ChunkResponse::NotFound,
+                };
+                if request.offset == 0 {
+                    println!("[Network] Sending {:?} to {:?}", request.key, peer);
+                }
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .file_transfer
+                    .send_response(channel, response);
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                if let Some(download) = self.downloads.remove(&request_id) {
+                    self.handle_chunk(download, response).await;
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                if let Some(download) = self.downloads.remove(&request_id) {
+                    self.download_failed(download, format!("{:?}", error)).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_chunk(&mut self, mut download: Download, response: ChunkResponse) {
+        let (total_size, data) = match response {
+            ChunkResponse::Chunk { total_size, data } => (total_size, data),
+            ChunkResponse::NotFound => {
+                return self.download_failed(download, "file not provided".into()).await;
+            }
+        };
+        // The size is announced by the providing peer, so it is checked before
+        // anything is written.
+        if total_size > self.max_file_size {
+            let reason = format!(
+                "file of {} bytes exceeds the maximum of {} bytes",
+                total_size, self.max_file_size
+            );
+            return self.download_failed(download, reason).await;
+        }
+        if download.received + data.len() as u64 > total_size {
+            return self.download_failed(download, "file changed during transfer".into()).await;
+        }
+        if let Err(e) = download.file.write_all(&data) {
+            return self.download_failed(download, e.to_string()).await;
+        }
+        download.received += data.len() as u64;
+        self.event_tx
+            .send(NetworkEvent::FileProgress {
+                peer: download.peer.to_base58(),
+                key: download.key.clone(),
+                received: download.received,
+                total: total_size,
+            })
+            .await
+            .unwrap();
+
+        if download.received < total_size && !data.is_empty() {
+            return self.request_next_chunk(download);
+        }
+        if download.received != total_size {
+            return self.download_failed(download, "file changed during transfer".into()).await;
+        }
+        if let Err(e) = download.file.sync_all() {
+            return self.download_failed(download, e.to_string()).await;
+        }
+        println!("[Network] Received {:?} from {:?}", download.key, download.peer);
+        self.event_tx
+            .send(NetworkEvent::FileReceived {
+                peer: download.peer.to_base58(),
+                key: download.key,
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn download_failed(&mut self, download: Download, reason: String) {
+        println!(
+            "[Network] Fetching {:?} from {:?} failed: {}",
+            download.key, download.peer, reason
+        );
+        self.event_tx
+            .send(NetworkEvent::FileTransferFailed {
+                peer: download.peer.to_base58(),
+                key: download.key,
+                reason,
+            })
+            .await
+            .unwrap();
+    }
 }
 
 // Custom `NetworkBehaviour`.
@@ -349,6 +833,9 @@ impl Network {
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event")]
 struct Behaviour {
+    // Denies connections to peers that are not whitelisted, together with
+    // the check of the transport.
+    gate: ConnectionGate,
     // Gossisub PubSub protocol.
     // Allows publishing messages in a network to a cerain topic.
     gossipsub: Gossipsub,
@@ -357,20 +844,62 @@ struct Behaviour {
     request_response: RequestResponse<Codec>,
     // Multicast DNS protocol for peer discovery in the local network.
     mdns: Mdns,
+    // Request Response protocol for transferring files in chunks.
+    file_transfer: RequestResponse<FileCodec>,
+    // Kademlia DHT, in which records are stored at the peers whose ids are
+    // closest to the record key.
+    kademlia: Kademlia<MemoryStore>,
 }
 
 impl Behaviour {
     // Create a new instance of a `Behaviour`.
-    async fn new(keypair: identity::Keypair) -> Result<Self, Box<dyn std::error::Error>> {
+    async fn new(
+        keypair: identity::Keypair,
+        max_message_size: usize,
+        record_config: RecordConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let local_peer_id = PeerId::from_public_key(&keypair.public());
+        let gossipsub_config = GossipsubConfigBuilder::default()
+            .max_transmit_size(max_message_size + GOSSIPSUB_OVERHEAD)
+            .build()
+            .unwrap();
         let gossipsub = Gossipsub::new(
             MessageAuthenticity::Signed(keypair),
-            GossipsubConfig::default(),
+            gossipsub_config,
         )
         .unwrap();
         let cfg = RequestResponseConfig::default();
-        let request_response = RequestResponse::new(Codec, iter::once((Protocol{}, ProtocolSupport::Full)), cfg);
+        let codec = Codec { max_message_size };
+        let request_response = RequestResponse::new(codec, iter::once((Protocol{}, ProtocolSupport::Full)), cfg);
         let mdns = Mdns::new(MdnsConfig::default()).await.unwrap();
-        let behaviour = Behaviour { gossipsub, mdns, request_response };
+        let gate = ConnectionGate::new();
+        let file_transfer = RequestResponse::new(
+            FileCodec,
+            iter::once((FileProtocol, ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        );
+        let mut kademlia_config = KademliaConfig::default();
+        kademlia_config
+            .set_replication_factor(
+                NonZeroUsize::new(record_config.replication_factor).unwrap_or(K_VALUE),
+            )
+            .set_record_ttl(Some(record_config.record_ttl))
+            // Republish our records well before they expire.
+            .set_publication_interval(Some(record_config.record_ttl / 2))
+            // Let the application validate records before they overwrite
+            // the ones that we store.
+            .set_record_filtering(KademliaStoreInserts::FilterBoth)
+            .set_max_packet_size(max_message_size + KADEMLIA_OVERHEAD);
+        let store_config = MemoryStoreConfig {
+            max_value_bytes: max_message_size,
+            ..Default::default()
+        };
+        let kademlia = Kademlia::with_config(
+            local_peer_id,
+            MemoryStore::with_config(local_peer_id, store_config),
+            kademlia_config,
+        );
+        let behaviour = Behaviour { gate, gossipsub, mdns, request_response, file_transfer, kademlia };
         Ok(behaviour)
     }
 }
@@ -380,7 +909,10 @@ impl Behaviour {
 enum Event {
     Mdns(MdnsEvent),
     Gossipsub(GossipsubEvent),
-    ReqRes(RequestResponseEvent<Vec<u8>, Ack>)
+    ReqRes(RequestResponseEvent<Vec<u8>, Ack>),
+    Gate(GateEvent),
+    FileTransfer(RequestResponseEvent<ChunkRequest, ChunkResponse>),
+    Kademlia(KademliaEvent),
 }
 
 impl From<MdnsEvent> for Event {
@@ -400,3 +932,21 @@ impl From<RequestResponseEvent<Vec<u8>, Ack>> for Event {
         Event::ReqRes(ev)
     }
 }
+
+impl From<GateEvent> for Event {
+    fn from(ev: GateEvent) -> Self {
+        Event::Gate(ev)
+    }
+}
+
+impl From<RequestResponseEvent<ChunkRequest, ChunkResponse>> for Event {
+    fn from(ev: RequestResponseEvent<ChunkRequest, ChunkResponse>) -> Self {
+        Event::FileTransfer(ev)
+    }
+}
+
+impl From<KademliaEvent> for Event {
+    fn from(ev: KademliaEvent) -> Self {
+        Event::Kademlia(ev)
+    }
+}
@@ -16,7 +16,10 @@ impl ProtocolName for Protocol {
 pub struct Ack;
 
 #[derive(Debug, Clone)]
-pub struct Codec;
+pub struct Codec {
+    // Maximum size of a request that we accept.
+    pub max_message_size: usize,
+}
 
 #[async_trait]
 impl RequestResponseCodec for Codec {
@@ -31,7 +34,7 @@ impl RequestResponseCodec for Codec {
     ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send {
-            let vec = read_length_prefixed(io, 1024).await?;
+            let vec = read_length_prefixed(io, self.max_message_size).await?;
             if vec.is_empty() {
                 return Err(io::ErrorKind::UnexpectedEof.into());
             }
@@ -0,0 +1,85 @@
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// On-disk store for the whitelist and the known addresses of peers, so that
+// they survive a restart.
+//
+// The store is a plain text file with one entry per line:
+// ```text
+// whitelisted <peer-id>
+// address <peer-id> <multiaddr>
+// ```
+// Lines that can not be parsed are skipped.
+pub struct PeerStore {
+    path: PathBuf,
+}
+
+impl PeerStore {
+    pub fn new(path: &Path) -> Self {
+        PeerStore { path: path.into() }
+    }
+
+    // Load the whitelist and address book from disk.
+    // Returns empty collections if the file does not exist yet.
+    pub fn load(&self) -> io::Result<(Vec<PeerId>, HashMap<PeerId, Vec<Multiaddr>>)> {
+        let mut whitelisted = Vec::new();
+        let mut addresses: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+        let content = match fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((whitelisted, addresses)),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next().map(PeerId::from_str)) {
+                (Some("whitelisted"), Some(Ok(peer))) => {
+                    if !whitelisted.contains(&peer) {
+                        whitelisted.push(peer);
+                    }
+                }
+                (Some("address"), Some(Ok(peer))) => {
+                    if let Some(Ok(addr)) = parts.next().map(Multiaddr::from_str) {
+                        let addrs = addresses.entry(peer).or_default();
+                        if !addrs.contains(&addr) {
+                            addrs.push(addr);
+                        }
+                    }
+                }
+                _ => println!("[Network] Skipping invalid peer store entry {:?}", line),
+            }
+        }
+        Ok((whitelisted, addresses))
+    }
+
+    // Write the whitelist and address book to disk.
+    //
+    // The data is first written to a temporary file that then replaces the
+    // old store. The file is synced before, so that a power loss never leaves
+    // a half-written store behind.
+    pub fn save(
+        &self,
+        whitelisted: &[PeerId],
+        addresses: &HashMap<PeerId, Vec<Multiaddr>>,
+    ) -> io::Result<()> {
+        let mut content = String::new();
+        for peer in whitelisted {
+            content.push_str(&format!("whitelisted {}\n", peer.to_base58()));
+        }
+        for (peer, addrs) in addresses {
+            for addr in addrs {
+                content.push_str(&format!("address {} {}\n", peer.to_base58(), addr));
+            }
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::{
+    core::{
+        upgrade::{read_length_prefixed, write_length_prefixed},
+        ProtocolName,
+    },
+    request_response::RequestResponseCodec,
+};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// Maximum number of bytes that are sent in one chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// Protocol for transferring files in chunks.
+//
+// The downloading peer requests one chunk after the other from the providing
+// peer, by sending the key under which the file is provided and the offset of
+// the next chunk.
+#[derive(Debug, Clone)]
+pub struct FileProtocol;
+impl ProtocolName for FileProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/digital-fax/file/0.1.0".as_ref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkRequest {
+    pub key: String,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum ChunkResponse {
+    Chunk { total_size: u64, data: Vec<u8> },
+    NotFound,
+}
+
+impl ChunkResponse {
+    // Read the chunk of a file that starts at `offset`.
+    pub fn read(path: &Path, offset: u64) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let total_size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(CHUNK_SIZE as u64).read_to_end(&mut data)?;
+        Ok(ChunkResponse::Chunk { total_size, data })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileCodec;
+
+#[async_trait]
+impl RequestResponseCodec for FileCodec {
+    type Protocol = FileProtocol;
+    type Request = ChunkRequest;
+    type Response = ChunkResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let vec = read_length_prefixed(io, 1024).await?;
+        if vec.len() < 8 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (offset, key) = vec.split_at(8);
+        let offset = u64::from_be_bytes(offset.try_into().unwrap());
+        let key = String::from_utf8(key.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(ChunkRequest { key, offset })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let vec = read_length_prefixed(io, CHUNK_SIZE + 9).await?;
+        match vec.split_first() {
+            Some((0, rest)) if rest.len() >= 8 => {
+                let (total_size, data) = rest.split_at(8);
+                let total_size = u64::from_be_bytes(total_size.try_into().unwrap());
+                Ok(ChunkResponse::Chunk {
+                    total_size,
+                    data: data.to_vec(),
+                })
+            }
+            Some((1, _)) => Ok(ChunkResponse::NotFound),
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut vec = req.offset.to_be_bytes().to_vec();
+        vec.extend_from_slice(req.key.as_bytes());
+        write_length_prefixed(io, vec).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let vec = match res {
+            ChunkResponse::Chunk { total_size, data } => {
+                let mut vec = vec![0];
+                vec.extend_from_slice(&total_size.to_be_bytes());
+                vec.extend_from_slice(&data);
+                vec
+            }
+            ChunkResponse::NotFound => vec![1],
+        };
+        write_length_prefixed(io, vec).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+}
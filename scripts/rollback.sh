@@ -0,0 +1,29 @@
+#!/bin/bash
+
+# Restores the previous management binary if an upgraded binary failed to
+# start multiple times without confirming its health.
+# Runs before every start of the digital-fax service.
+
+MAX_ATTEMPTS=3
+
+BINARY=$1
+DIRECTORY="$(dirname "$BINARY")"
+PENDING="$DIRECTORY/.management.pending"
+BACKUP="$DIRECTORY/.management.backup"
+
+if [[ ! -f "$PENDING" ]]; then
+    exit 0
+fi
+
+ATTEMPTS=$(cat "$PENDING")
+if [[ "$ATTEMPTS" -ge "$MAX_ATTEMPTS" ]] && [[ -f "$BACKUP" ]]; then
+    echo "Upgraded binary failed to start $ATTEMPTS times, restoring previous binary"
+    mv "$BACKUP" "$BINARY"
+    rm -f "$BINARY.release"
+    if [[ -f "$BACKUP.release" ]]; then
+        mv "$BACKUP.release" "$BINARY.release"
+    fi
+    rm "$PENDING"
+else
+    echo $((ATTEMPTS + 1)) > "$PENDING"
+fi
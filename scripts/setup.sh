@@ -6,6 +6,7 @@ cd $SCRIPT_DIRECTORY
 hostname=$1
 
 scp ../digital-fax.service pi@$hostname:~
+scp ./rollback.sh pi@$hostname:~
 scp ../management/arm-binary pi@$hostname:~/management
 
 ssh pi@$hostname << EOF
@@ -47,11 +47,13 @@ $('#send').on('click', () => {
     peer = $('#send-peer').val();
     console.log(input);
 
+    $('#send-result').text('');
     invoke('publish_message', {
             message: input,
             peer: peer,
         })
-        .then((_) => {})
+        .then((_) => $('#send-result').text(peer ? 'Zugestellt' : 'Gesendet'))
+        .catch((e) => $('#send-result').text(`Fehler: ${e}`))
 });
 
 $('#whitelist').on('click', () => {
@@ -61,7 +63,7 @@ $('#whitelist').on('click', () => {
         peer: peer,
     }).then((_) => {
         document.getElementById("whitelist-peer").value = "";
-    })
+    }).catch((e) => alert(`Fehler: ${e}`))
 });
 
 $('#auth').on('click', () => {
@@ -72,7 +74,7 @@ $('#auth').on('click', () => {
         peer: peer,
     }).then((_) => {
         document.getElementById("auth-peer").value = "";
-    })
+    }).catch((e) => alert(`Fehler: ${e}`))
 });
 
 $('#alias').on('click', () => {
@@ -7,7 +7,8 @@ use futures::{
     channel::{mpsc, oneshot},
     SinkExt,
 };
-use management::{Management, UserCommand};
+use management::{Config, Management, Role, UserCommand};
+use serde::Serialize;
 use tauri::{State, Submenu, CustomMenuItem, Menu, MenuItem, WindowBuilder};
 
 struct CommandHandler {
@@ -16,31 +17,95 @@ struct CommandHandler {
 
 impl CommandHandler {
     fn new() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let config = match Config::from_args(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Could not load config: {}", e);
+                std::process::exit(1);
+            }
+        };
         let (user_cmd_tx, user_cmd_rx) = mpsc::channel(0);
-        tauri::async_runtime::spawn(Management::new(user_cmd_rx).run());
+        let management = match Management::new(config, user_cmd_rx) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("Could not start network: {}", e);
+                std::process::exit(1);
+            }
+        };
+        tauri::async_runtime::spawn(management.run());
         CommandHandler { user_cmd_tx }
     }
 }
 
+// Resolves once the message was delivered to `peer`, or published to the
+// whole network if no peer is given. The message is displayed from
+// `not_before` until `expires_at`, both unix time in seconds, if given.
 #[tauri::command]
-fn publish_message(message: String, peer: Option<String>, handler: State<CommandHandler>) {
+async fn publish_message(
+    message: String,
+    peer: Option<String>,
+    not_before: Option<u64>,
+    expires_at: Option<u64>,
+    handler: State<'_, CommandHandler>,
+) -> Result<(), String> {
     let mut user_cmd_tx = handler.user_cmd_tx.clone();
-    let command = UserCommand::SendMsg { peer, message };
-    tauri::async_runtime::block_on(user_cmd_tx.send(command)).unwrap();
+    let peer = peer.filter(|p| !p.is_empty());
+    let (result_tx, result_rx) = oneshot::channel();
+    let command = UserCommand::SendMsg {
+        peer,
+        message,
+        not_before,
+        expires_at,
+        result_tx,
+    };
+    user_cmd_tx.send(command).await.unwrap();
+    result_rx.await.unwrap()
 }
 
-#[tauri::command]
-fn whitelist(peer: String, handler: State<CommandHandler>) {
+// Send a command that addresses a peer and wait until it was carried out.
+fn run_command(
+    handler: &CommandHandler,
+    command: impl FnOnce(oneshot::Sender<Result<(), String>>) -> UserCommand,
+) -> Result<(), String> {
     let mut user_cmd_tx = handler.user_cmd_tx.clone();
-    let command = UserCommand::Whitelist(peer);
-    tauri::async_runtime::block_on(user_cmd_tx.send(command)).unwrap();
+    let (tx, rx) = oneshot::channel();
+    let fut = async {
+        user_cmd_tx.send(command(tx)).await.unwrap();
+        rx.await.unwrap()
+    };
+    tauri::async_runtime::block_on(fut)
 }
 
 #[tauri::command]
-fn authorize(peer: String, handler: State<CommandHandler>) {
-    let mut user_cmd_tx = handler.user_cmd_tx.clone();
-    let command = UserCommand::Authorize(peer);
-    tauri::async_runtime::block_on(user_cmd_tx.send(command)).unwrap();
+fn whitelist(peer: String, handler: State<CommandHandler>) -> Result<(), String> {
+    run_command(&handler, |tx| UserCommand::Whitelist(peer, tx))
+}
+
+#[tauri::command]
+fn unwhitelist(peer: String, handler: State<CommandHandler>) -> Result<(), String> {
+    run_command(&handler, |tx| UserCommand::Unwhitelist(peer, tx))
+}
+
+#[tauri::command]
+fn authorize(peer: String, handler: State<CommandHandler>) -> Result<(), String> {
+    run_command(&handler, |tx| UserCommand::AssignRole(peer, Role::Publisher, tx))
+}
+
+#[tauri::command]
+fn deauthorize(peer: String, handler: State<CommandHandler>) -> Result<(), String> {
+    run_command(&handler, |tx| UserCommand::AssignRole(peer, Role::Viewer, tx))
+}
+
+#[tauri::command]
+fn assign_role(peer: String, role: String, handler: State<CommandHandler>) -> Result<(), String> {
+    let role = match role.as_str() {
+        "admin" => Role::Admin,
+        "publisher" => Role::Publisher,
+        "viewer" => Role::Viewer,
+        _ => return Err(format!("Unknown role {:?}", role)),
+    };
+    run_command(&handler, |tx| UserCommand::AssignRole(peer, role, tx))
 }
 
 #[tauri::command]
@@ -50,6 +115,12 @@ fn alias(alias: String, handler: State<CommandHandler>) {
     tauri::async_runtime::block_on(user_cmd_tx.send(command)).unwrap();
 }
 
+#[tauri::command]
+fn unalias(handler: State<CommandHandler>) {
+    let mut user_cmd_tx = handler.user_cmd_tx.clone();
+    tauri::async_runtime::block_on(user_cmd_tx.send(UserCommand::Unalias)).unwrap();
+}
+
 #[tauri::command]
 fn get_local_id(handler: State<CommandHandler>) -> String {
     let mut user_cmd_tx = handler.user_cmd_tx.clone();
@@ -62,6 +133,33 @@ fn get_local_id(handler: State<CommandHandler>) -> String {
     tauri::async_runtime::block_on(fut)
 }
 
+// What a display is currently showing.
+#[derive(Serialize)]
+struct DisplayContent {
+    payload: String,
+    author: String,
+    timestamp: u64,
+}
+
+// Ask a peer what it currently displays. Resolves to `None` if it displays
+// nothing yet.
+#[tauri::command]
+async fn get_display_content(
+    peer: String,
+    handler: State<'_, CommandHandler>,
+) -> Result<Option<DisplayContent>, String> {
+    let mut user_cmd_tx = handler.user_cmd_tx.clone();
+    let (tx, rx) = oneshot::channel();
+    let command = UserCommand::GetDisplayContent(peer, tx);
+    user_cmd_tx.send(command).await.unwrap();
+    let content = rx.await.unwrap()?;
+    Ok(content.map(|c| DisplayContent {
+        payload: c.payload,
+        author: c.author,
+        timestamp: c.timestamp,
+    }))
+}
+
 fn main() {
     tauri::Builder::default()
         .menu(tauri::Menu::os_default("Digital Fax"))
@@ -69,9 +167,14 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             publish_message,
             whitelist,
+            unwhitelist,
             authorize,
+            deauthorize,
+            assign_role,
             get_local_id,
-            alias
+            get_display_content,
+            alias,
+            unalias
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");